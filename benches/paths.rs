@@ -0,0 +1,126 @@
+//! Times the evaluation of an expression over an image by each of the faster
+//! paths against the interpreter, on a single thread and without any file I/O.
+//! Run with `cargo bench`, adding `--features jit` includes native code.
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use glitch::compile::Compiled;
+use glitch::edge::EdgeMode;
+use glitch::eval::{eval, EvalContext, Inputs, Machine};
+use glitch::kernels::Kernels;
+use glitch::parser::parse;
+use glitch::simd::RowMachine;
+
+const EDGE: EdgeMode = EdgeMode::Clamp;
+
+/// The fastest of a few runs, which is the least disturbed by the rest of the
+/// machine, along with the output.
+fn time(mut run: impl FnMut() -> Vec<u8>) -> (Duration, Vec<u8>) {
+    let mut best = Duration::MAX;
+    let mut out = Vec::new();
+    for _ in 0..3 {
+        let start = Instant::now();
+        out = run();
+        best = best.min(start.elapsed());
+    }
+    (best, out)
+}
+
+/// Every pixel through [`eval`], one after the other.
+fn interpreted(program: &Compiled, img: &DynamicImage) -> Vec<u8> {
+    let kernels = Kernels::new(img, EDGE).with_convolutions(&program.kernels);
+    kernels.prepare(program);
+    let mut machine = Machine::new(program);
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let mut out = Vec::with_capacity(img.width() as usize * img.height() as usize * 4);
+    for y in 0..img.height() {
+        for x in 0..img.width() {
+            let ctx = EvalContext {
+                program,
+                machine: &mut machine,
+                size: img.dimensions(),
+                rgba: img.get_pixel(x, y).0,
+                saved_rgb: [0; 3],
+                position: (x, y),
+                edge: EDGE,
+                kernels: &kernels,
+                rng: &mut rng,
+                seed: 0,
+                frame: 0,
+            };
+            out.extend(eval(ctx, img).unwrap().0);
+        }
+    }
+    out
+}
+
+fn rows(program: &Compiled, img: &DynamicImage) -> Vec<u8> {
+    let kernels = Kernels::new(img, EDGE).with_convolutions(&program.kernels);
+    kernels.prepare(program);
+    let mut machine = RowMachine::new(program, img.width() as usize);
+    let mut inputs = Inputs::new(img, EDGE, &kernels, 0, 0);
+    let mut out = Vec::with_capacity(img.width() as usize * img.height() as usize * 4);
+    for y in 0..img.height() {
+        let row = machine.eval(program, &mut inputs, img, y, 0..img.width());
+        out.extend(row.iter().flat_map(|pixel| pixel.0));
+    }
+    out
+}
+
+#[cfg(feature = "jit")]
+fn native(kernel: &glitch::jit::Kernel, img: &DynamicImage) -> Vec<u8> {
+    let input = img.to_rgba8().into_raw();
+    let mut out = vec![0; input.len()];
+    let width = img.width();
+    for (y, row) in out.chunks_exact_mut(width as usize * 4).enumerate() {
+        kernel.run(&input, img.dimensions(), 0, y as u32, 0..width, row);
+    }
+    out
+}
+
+/// Prints how much faster `fast` evaluates `expr` than the interpreter, after
+/// checking they give the same output.
+fn compare(name: &str, expr: &str, img: &DynamicImage, fast: impl FnMut() -> Vec<u8>) {
+    let program = Compiled::new(&parse(expr).unwrap());
+    let (interpreter, expected) = time(|| interpreted(&program, img));
+    let (fast, out) = time(fast);
+    assert!(out == expected, "{} differs from the interpreter", name);
+    println!(
+        "{}: {:?}, interpreter: {:?}, {:.1}x faster",
+        name,
+        fast,
+        interpreter,
+        interpreter.as_secs_f64() / fast.as_secs_f64()
+    );
+}
+
+fn main() {
+    let img = DynamicImage::from(RgbaImage::from_fn(1024, 1024, |x, y| {
+        let a = if (x + y) % 7 == 6 {
+            0
+        } else {
+            255 - (x as u8 & 15)
+        };
+        Rgba([
+            (x * 37 + y * 11) as u8,
+            (x * y * 5 + 3) as u8,
+            (x ^ y) as u8,
+            a,
+        ])
+    }));
+
+    let expr = "let k = (c * 3 + x * y) ^ (c >> 2); min(k - (y & c), 200) | (k + b) / 3";
+    let program = Compiled::new(&parse(expr).unwrap());
+    compare("rows", expr, &img, || rows(&program, &img));
+
+    #[cfg(feature = "jit")]
+    {
+        let expr = "let k = (c + x * y) ^ h; if k > Y then k - v else lerp(k, d, x) @ 200";
+        let kernel = glitch::jit::Kernel::new(&parse(expr).unwrap()).unwrap();
+        compare("native", expr, &img, || native(&kernel, &img));
+    }
+}
@@ -0,0 +1,713 @@
+use std::fmt;
+
+use crate::parser::Token;
+
+/// Byte range of a node in the source expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Returns the smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitAndNot,
+    BitXor,
+    BitLShift,
+    BitRShift,
+    Greater,
+    Weight,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
+}
+
+impl BinOp {
+    /// Binding power of the operator, all operators are left associative.
+    ///
+    /// `?` keeps the go-glitch precedence, the other comparisons bind looser than
+    /// arithmetic so `x + 5 < y` compares the sum.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Lt | BinOp::Le | BinOp::Eq | BinOp::Ne | BinOp::Ge | BinOp::Gt => 3,
+            BinOp::Add | BinOp::Sub | BinOp::BitOr | BinOp::BitXor => 4,
+            BinOp::Mul
+            | BinOp::Div
+            | BinOp::Mod
+            | BinOp::BitAnd
+            | BinOp::BitAndNot
+            | BinOp::BitLShift
+            | BinOp::BitRShift
+            | BinOp::Pow => 5,
+            BinOp::Greater | BinOp::Weight => 6,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "#",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitAndNot => ":",
+            BinOp::BitXor => "^",
+            BinOp::BitLShift => "<<",
+            BinOp::BitRShift => ">>",
+            BinOp::Greater => "?",
+            BinOp::Weight => "@",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Ge => ">=",
+            BinOp::Gt => ">",
+        }
+    }
+
+    pub fn token(self) -> Token {
+        match self {
+            BinOp::Add => Token::Add,
+            BinOp::Sub => Token::Sub,
+            BinOp::Mul => Token::Mul,
+            BinOp::Div => Token::Div,
+            BinOp::Mod => Token::Mod,
+            BinOp::Pow => Token::Pow,
+            BinOp::BitAnd => Token::BitAnd,
+            BinOp::BitOr => Token::BitOr,
+            BinOp::BitAndNot => Token::BitAndNot,
+            BinOp::BitXor => Token::BitXor,
+            BinOp::BitLShift => Token::BitLShift,
+            BinOp::BitRShift => Token::BitRShift,
+            BinOp::Greater => Token::Greater,
+            BinOp::Weight => Token::Weight,
+            BinOp::Lt => Token::Lt,
+            BinOp::Le => Token::Le,
+            BinOp::Eq => Token::Eq,
+            BinOp::Ne => Token::Ne,
+            BinOp::Ge => Token::Ge,
+            BinOp::Gt => Token::Gt,
+        }
+    }
+}
+
+/// Prefix operators, all of them wrap within `u8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnOp {
+    /// `-c`, two's complement negation.
+    Neg,
+    /// `~c`, bitwise complement, the same as `255 - c`.
+    Not,
+    /// `abs c`, the magnitude of the value read as a signed byte, so
+    /// `abs (c - b)` is the distance between `c` and `b`.
+    Abs,
+}
+
+impl UnOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+            UnOp::Not => "~",
+            UnOp::Abs => "abs ",
+        }
+    }
+
+    pub fn token(self) -> Token {
+        match self {
+            UnOp::Neg => Token::Neg,
+            UnOp::Not => Token::BitNot,
+            UnOp::Abs => Token::Abs,
+        }
+    }
+}
+
+/// Input variables, each has a name and the ones from go-glitch also the single
+/// letter alias they had there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Var {
+    Color,
+    Blur,
+    HFlip,
+    VFlip,
+    DFlip,
+    Lum,
+    Noise,
+    Red,
+    Green,
+    Blue,
+    Saved,
+    Rand,
+    Edge,
+    X,
+    Y,
+    High,
+    Low,
+    Frame,
+}
+
+const VARS: [(Var, Option<char>, &str); 18] = [
+    (Var::Color, Some('c'), "color"),
+    (Var::Blur, Some('b'), "blur"),
+    (Var::HFlip, Some('h'), "hflip"),
+    (Var::VFlip, Some('v'), "vflip"),
+    (Var::DFlip, Some('d'), "dflip"),
+    (Var::Lum, Some('Y'), "lum"),
+    (Var::Noise, Some('N'), "noise"),
+    (Var::Red, Some('R'), "red"),
+    (Var::Green, Some('G'), "green"),
+    (Var::Blue, Some('B'), "blue"),
+    (Var::Saved, Some('s'), "saved"),
+    (Var::Rand, Some('r'), "rand"),
+    (Var::Edge, Some('e'), "edge"),
+    (Var::X, Some('x'), "x"),
+    (Var::Y, Some('y'), "y"),
+    (Var::High, Some('H'), "high"),
+    (Var::Low, Some('L'), "low"),
+    (Var::Frame, None, "frame"),
+];
+
+impl Var {
+    /// Looks up a variable by its name or its single letter alias.
+    pub fn from_name(name: &str) -> Option<Var> {
+        VARS.iter()
+            .find(|(_, letter, long)| {
+                *long == name || letter.is_some_and(|c| name == c.to_string())
+            })
+            .map(|(var, _, _)| *var)
+    }
+
+    /// Whether the variable is read from the image around the current pixel, only
+    /// those can be sampled at an offset like `c[1, 0]`.
+    pub fn is_spatial(self) -> bool {
+        matches!(
+            self,
+            Var::Color
+                | Var::Blur
+                | Var::HFlip
+                | Var::VFlip
+                | Var::DFlip
+                | Var::Lum
+                | Var::Edge
+                | Var::High
+                | Var::Low
+        )
+    }
+}
+
+/// The single letter of the variable, or its name when it has none.
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, letter, name) = VARS.iter().find(|(var, _, _)| var == self).unwrap();
+        match letter {
+            Some(letter) => write!(f, "{}", letter),
+            None => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Built-in functions, evaluated independently for each color component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Func {
+    /// `min(a, b, ...)`
+    Min,
+    /// `max(a, b, ...)`
+    Max,
+    /// `clamp(v, lo, hi)`
+    Clamp,
+    /// `avg(a, b, ...)`, the mean rounded down.
+    Avg,
+    /// `lerp(a, b, t)`, `a` when `t` is 0 and `b` when `t` is 255.
+    Lerp,
+    /// `hash()` or `hash(salt)`, white noise fixed to each pixel and frame.
+    Hash,
+    /// `vnoise(scale)`, value noise with points `scale` pixels apart.
+    ValueNoise,
+    /// `perlin(scale)`, gradient noise with features about `scale` pixels wide.
+    Perlin,
+    /// `blur(radius)`, `b` over a box of `2 * radius + 1` pixels.
+    Blur,
+    /// `edge(radius)`, `e` over a box of `2 * radius + 1` pixels.
+    Edge,
+    /// `high(radius)`, `H` over a box of `2 * radius + 1` pixels.
+    High,
+    /// `low(radius)`, `L` over a box of `2 * radius + 1` pixels.
+    Low,
+    /// `gauss(sigma)`, a Gaussian blur with a deviation of `sigma` pixels.
+    Gauss,
+    /// `sobel()`, the magnitude of the Sobel gradient.
+    Sobel,
+    /// `angle()`, the direction of the Sobel gradient, a full turn being 256.
+    Angle,
+    /// `laplace()`, the absolute value of the 4 neighbor Laplacian.
+    Laplace,
+    /// `unsharp(sigma)` or `unsharp(sigma, amount)`, sharpens by adding the
+    /// difference from `gauss(sigma)`, `amount` percent of it.
+    Unsharp,
+}
+
+impl Func {
+    pub fn from_name(name: &str) -> Option<Func> {
+        match name {
+            "min" => Some(Func::Min),
+            "max" => Some(Func::Max),
+            "clamp" => Some(Func::Clamp),
+            "avg" => Some(Func::Avg),
+            "lerp" => Some(Func::Lerp),
+            "hash" => Some(Func::Hash),
+            "vnoise" => Some(Func::ValueNoise),
+            "perlin" => Some(Func::Perlin),
+            "gauss" => Some(Func::Gauss),
+            "sobel" => Some(Func::Sobel),
+            "angle" => Some(Func::Angle),
+            "laplace" => Some(Func::Laplace),
+            "unsharp" => Some(Func::Unsharp),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Func::Min => "min",
+            Func::Max => "max",
+            Func::Clamp => "clamp",
+            Func::Avg => "avg",
+            Func::Lerp => "lerp",
+            Func::Hash => "hash",
+            Func::ValueNoise => "vnoise",
+            Func::Perlin => "perlin",
+            Func::Blur => "blur",
+            Func::Edge => "edge",
+            Func::High => "high",
+            Func::Low => "low",
+            Func::Gauss => "gauss",
+            Func::Sobel => "sobel",
+            Func::Angle => "angle",
+            Func::Laplace => "laplace",
+            Func::Unsharp => "unsharp",
+        }
+    }
+
+    /// Minimum and maximum number of arguments, `None` if there is no upper bound.
+    pub fn arity(self) -> (usize, Option<usize>) {
+        match self {
+            Func::Min | Func::Max | Func::Avg => (2, None),
+            Func::Clamp | Func::Lerp => (3, Some(3)),
+            Func::Hash => (0, Some(1)),
+            Func::ValueNoise | Func::Perlin => (1, Some(1)),
+            Func::Blur | Func::Edge | Func::High | Func::Low | Func::Gauss => (1, Some(1)),
+            Func::Sobel | Func::Angle | Func::Laplace => (0, Some(0)),
+            Func::Unsharp => (1, Some(2)),
+        }
+    }
+
+    /// The function taking a radius for a 3x3 neighborhood variable, written with
+    /// the name of the variable like `blur(4)` or `H(2)`. These names lex as
+    /// variables, so [`Func::from_name`] does not know them.
+    pub fn with_radius(var: Var) -> Option<Func> {
+        match var {
+            Var::Blur => Some(Func::Blur),
+            Var::Edge => Some(Func::Edge),
+            Var::High => Some(Func::High),
+            Var::Low => Some(Func::Low),
+            _ => None,
+        }
+    }
+
+    /// Whether the function is a noise field, see [`crate::noise::Field`].
+    pub fn is_noise(self) -> bool {
+        matches!(self, Func::Hash | Func::ValueNoise | Func::Perlin)
+    }
+
+    /// Whether the function reads a neighborhood of the image.
+    pub fn is_kernel(self) -> bool {
+        matches!(
+            self,
+            Func::Blur
+                | Func::Edge
+                | Func::High
+                | Func::Low
+                | Func::Gauss
+                | Func::Sobel
+                | Func::Angle
+                | Func::Laplace
+                | Func::Unsharp
+        )
+    }
+
+    /// Whether the result depends on the pixel being evaluated, not only the
+    /// arguments.
+    pub fn reads_pixel(self) -> bool {
+        self.is_noise() || self.is_kernel()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprKind {
+    Num(u8),
+    Var(Var),
+    /// A reference to a `let` binding, resolved to its slot while parsing.
+    Local {
+        slot: usize,
+        name: String,
+    },
+    /// The image convolved with a `kernel` declaration, resolved to its index in
+    /// [`Program::kernels`] while parsing.
+    Convolve {
+        slot: usize,
+        name: String,
+    },
+    Unary {
+        op: UnOp,
+        operand: Box<Expr>,
+    },
+    /// `c.g` or `h.bgr`, component `i` of the result is component `lanes[i]` of
+    /// the operand.
+    Swizzle {
+        operand: Box<Expr>,
+        lanes: [u8; 3],
+    },
+    /// `c[dx, dy]`, the variable evaluated at the pixel moved by the red component
+    /// of each offset read as a signed byte.
+    Offset {
+        var: Var,
+        dx: Box<Expr>,
+        dy: Box<Expr>,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Call {
+        func: Func,
+        args: Vec<Expr>,
+    },
+    /// `if cond then a else b`, chosen separately for each color component.
+    Cond {
+        cond: Box<Expr>,
+        if_true: Box<Expr>,
+        if_false: Box<Expr>,
+    },
+    /// `[r, g, b]`, component `i` of the color is component `i` of item `i`. A
+    /// fourth item for alpha is moved to [`Program::alpha`] while parsing.
+    Channels(Vec<Expr>),
+    Group(Box<Expr>),
+}
+
+/// A node of a parsed expression together with the source it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+
+    fn push_rpn(&self, out: &mut Vec<(Token, Span)>) {
+        match &self.kind {
+            ExprKind::Num(n) => out.push((Token::Num(*n), self.span)),
+            ExprKind::Var(var) => out.push((Token::Var(*var), self.span)),
+            ExprKind::Local { slot, .. } => out.push((Token::Local(*slot), self.span)),
+            ExprKind::Convolve { slot, .. } => out.push((Token::Convolve(*slot), self.span)),
+            ExprKind::Unary { op, operand } => {
+                operand.push_rpn(out);
+                out.push((op.token(), self.span));
+            }
+            ExprKind::Swizzle { operand, lanes } => {
+                operand.push_rpn(out);
+                out.push((Token::Swizzle(*lanes), self.span));
+            }
+            ExprKind::Offset { var, dx, dy } => {
+                dx.push_rpn(out);
+                dy.push_rpn(out);
+                out.push((Token::Sample(*var), self.span));
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                lhs.push_rpn(out);
+                rhs.push_rpn(out);
+                out.push((op.token(), self.span));
+            }
+            ExprKind::Call { func, args } => {
+                for arg in args {
+                    arg.push_rpn(out);
+                }
+                out.push((Token::Call(*func, args.len()), self.span));
+            }
+            ExprKind::Cond {
+                cond,
+                if_true,
+                if_false,
+            } => {
+                cond.push_rpn(out);
+                if_true.push_rpn(out);
+                if_false.push_rpn(out);
+                out.push((Token::Select, self.span));
+            }
+            ExprKind::Channels(items) => {
+                for item in items {
+                    item.push_rpn(out);
+                }
+                out.push((Token::Channels, self.span));
+            }
+            ExprKind::Group(inner) => inner.push_rpn(out),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Num(n) => write!(f, "{}", n),
+            ExprKind::Var(var) => write!(f, "{}", var),
+            ExprKind::Local { name, .. } | ExprKind::Convolve { name, .. } => write!(f, "{}", name),
+            ExprKind::Unary { op, operand } => {
+                let parens = matches!(
+                    operand.kind,
+                    ExprKind::Binary { .. } | ExprKind::Cond { .. }
+                );
+                write!(f, "{}{}", op.symbol(), Parens(operand, parens))
+            }
+            ExprKind::Swizzle { operand, lanes } => {
+                let parens = matches!(
+                    operand.kind,
+                    ExprKind::Binary { .. } | ExprKind::Cond { .. } | ExprKind::Unary { .. }
+                );
+                write!(f, "{}.", Parens(operand, parens))?;
+                let name = |lane: u8| ['r', 'g', 'b'][lane as usize];
+                if lanes.iter().all(|lane| *lane == lanes[0]) {
+                    write!(f, "{}", name(lanes[0]))
+                } else {
+                    lanes
+                        .iter()
+                        .try_for_each(|lane| write!(f, "{}", name(*lane)))
+                }
+            }
+            ExprKind::Offset { var, dx, dy } => write!(f, "{}[{}, {}]", var, dx, dy),
+            ExprKind::Binary { op, lhs, rhs } => {
+                // Operators are left associative, so an operand on the right also
+                // needs parenthesis at the same precedence.
+                let parens = |operand: &Expr, right: bool| match &operand.kind {
+                    ExprKind::Binary { op: inner, .. } => {
+                        inner.precedence() < op.precedence()
+                            || right && inner.precedence() == op.precedence()
+                    }
+                    ExprKind::Cond { .. } => true,
+                    _ => false,
+                };
+                write!(
+                    f,
+                    "{} {} {}",
+                    Parens(lhs, parens(lhs, false)),
+                    op.symbol(),
+                    Parens(rhs, parens(rhs, true))
+                )
+            }
+            ExprKind::Call { func, args } => write!(f, "{}({})", func.name(), List(args)),
+            ExprKind::Cond {
+                cond,
+                if_true,
+                if_false,
+            } => write!(f, "if {} then {} else {}", cond, if_true, if_false),
+            ExprKind::Channels(items) => write!(f, "[{}]", List(items)),
+            ExprKind::Group(inner) => write!(f, "({})", inner),
+        }
+    }
+}
+
+/// An operand written in parenthesis when it would otherwise bind differently.
+/// The parser keeps the parenthesis of the source as [`ExprKind::Group`], so
+/// this only matters for trees built or rewritten after parsing.
+struct Parens<'a>(&'a Expr, bool);
+
+impl fmt::Display for Parens<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            true => write!(f, "({})", self.0),
+            false => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Comma separated expressions.
+struct List<'a>(&'a [Expr]);
+
+impl fmt::Display for List<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+/// `let name = value;`, the value is computed at most once per pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub name: String,
+    pub value: Expr,
+    pub span: Span,
+}
+
+/// What a convolution does with a result outside of `[0, 255]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wraps around like the arithmetic operators.
+    Wrap,
+    /// Saturates at 0 and 255.
+    Clamp,
+}
+
+/// `kernel name = [[w, ...], ...] / divisor;`, convolving the image with a matrix
+/// of weights. The sum is divided rounding down, then wraps or is clamped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Convolution {
+    pub name: String,
+    /// Rows of weights from top to bottom, all of the same length. Both sizes are
+    /// odd so the pixel itself is in the middle.
+    pub weights: Vec<Vec<i16>>,
+    pub divisor: u8,
+    pub overflow: Overflow,
+    pub span: Span,
+}
+
+impl fmt::Display for Convolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "kernel {} = [", self.name)?;
+        for (i, row) in self.weights.iter().enumerate() {
+            let row: Vec<String> = row.iter().map(|w| w.to_string()).collect();
+            match i {
+                0 => write!(f, "[{}]", row.join(", "))?,
+                _ => write!(f, ", [{}]", row.join(", "))?,
+            }
+        }
+        write!(f, "] / {}", self.divisor)?;
+        match self.overflow {
+            Overflow::Wrap => write!(f, ";"),
+            Overflow::Clamp => write!(f, " clamp;"),
+        }
+    }
+}
+
+/// Bindings followed by the expression giving the color of each pixel. Binding
+/// `i` is stored in slot `i` and can only refer to the ones before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// Convolutions the expressions can use, by index.
+    pub kernels: Vec<Convolution>,
+    pub bindings: Vec<Binding>,
+    pub body: Expr,
+    /// Set by a body of the form `[r, g, b, a]`, otherwise alpha is kept.
+    pub alpha: Option<Expr>,
+    /// The second half of a body of the form `x, y`. The program then gives the
+    /// coordinate each pixel is read from, `body` being the x coordinate.
+    pub remap: Option<Expr>,
+}
+
+impl Program {
+    pub fn new(bindings: Vec<Binding>, mut body: Expr) -> Self {
+        let alpha = match &mut body.kind {
+            ExprKind::Channels(items) if items.len() == 4 => items.pop(),
+            _ => None,
+        };
+
+        Program {
+            kernels: Vec::new(),
+            bindings,
+            body,
+            alpha,
+            remap: None,
+        }
+    }
+
+    pub fn with_remap(bindings: Vec<Binding>, x: Expr, y: Expr) -> Self {
+        Program {
+            kernels: Vec::new(),
+            bindings,
+            body: x,
+            alpha: None,
+            remap: Some(y),
+        }
+    }
+
+    /// Flattens the tree back into the postfix token order produced by the old
+    /// shunting yard parser.
+    pub fn to_rpn(&self) -> Vec<Token> {
+        self.to_rpn_spanned()
+            .into_iter()
+            .map(|(tok, _)| tok)
+            .collect()
+    }
+
+    /// Each binding is emitted as its value followed by a [`Token::Store`] into
+    /// its slot, then the body.
+    pub fn to_rpn_spanned(&self) -> Vec<(Token, Span)> {
+        let mut out = Vec::new();
+        for (slot, binding) in self.bindings.iter().enumerate() {
+            binding.value.push_rpn(&mut out);
+            out.push((Token::Store(slot), binding.span));
+        }
+        self.body.push_rpn(&mut out);
+        if let Some(alpha) = &self.alpha {
+            alpha.push_rpn(&mut out);
+            out.push((Token::Alpha, alpha.span));
+        }
+        if let Some(y) = &self.remap {
+            y.push_rpn(&mut out);
+            out.push((Token::Remap, y.span));
+        }
+        out
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for kernel in &self.kernels {
+            write!(f, "{} ", kernel)?;
+        }
+        for binding in &self.bindings {
+            write!(f, "let {} = {}; ", binding.name, binding.value)?;
+        }
+        match (&self.body.kind, &self.alpha) {
+            (ExprKind::Channels(items), Some(alpha)) => write!(f, "[{}, {}]", List(items), alpha)?,
+            _ => write!(f, "{}", self.body)?,
+        }
+        match &self.remap {
+            Some(y) => write!(f, ", {}", y),
+            None => Ok(()),
+        }
+    }
+}
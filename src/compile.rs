@@ -0,0 +1,213 @@
+use crate::ast::{BinOp, Convolution, Expr, ExprKind, Func, Program, UnOp, Var};
+
+/// One instruction of a compiled program, working on a stack of colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Num(u8),
+    Var(Var),
+    /// Pushes the value of a binding, running its code first if it was not used
+    /// yet at this pixel.
+    Local(usize),
+    /// Pushes the image convolved with a kernel of the program.
+    Convolve(usize),
+    /// Ends the code of a binding, saving the value on top of the stack in its
+    /// slot and going back to where it was used.
+    Return(usize),
+    Unary(UnOp),
+    Swizzle([u8; 3]),
+    Binary(BinOp),
+    Call(Func, usize),
+    /// Pops the y and x offsets and pushes the variable sampled there.
+    Offset(Var),
+    /// Pops three values and takes component `i` of the color from the `i`th.
+    Channels,
+    /// Jumps over the true branch of an `if` when the mask on top of the stack is
+    /// zero in every component, pushing a placeholder for it instead.
+    SkipIfNone(usize),
+    /// Jumps over the false branch when the mask below the true value is non zero
+    /// in every component, pushing a placeholder for it instead.
+    SkipIfAll(usize),
+    /// Pops the mask, the true and the false value and picks per component.
+    Select,
+    /// Ends an entry point, its result is the value on top of the stack.
+    Halt,
+}
+
+/// A program flattened into one code array, compiled once per expression and
+/// shared by every pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compiled {
+    pub code: Vec<Op>,
+    pub body: usize,
+    pub alpha: Option<usize>,
+    /// Entry of the y coordinate of a program of the form `x, y`.
+    pub remap: Option<usize>,
+    /// Entry of the code of each binding, ending with [`Op::Return`].
+    pub bindings: Vec<usize>,
+    /// Read by [`Op::Convolve`].
+    pub kernels: Vec<Convolution>,
+    /// Most values on the stack at once, so it can be allocated up front.
+    pub max_stack: usize,
+}
+
+impl Compiled {
+    pub fn new(program: &Program) -> Self {
+        let mut code = Vec::new();
+        let mut depths = Vec::new();
+
+        let mut entry = |expr: &Expr, end: Op, code: &mut Vec<Op>| {
+            let start = code.len();
+            emit(expr, code);
+            code.push(end);
+            depths.push(max_depth(&code[start..]));
+            start
+        };
+
+        let body = entry(&program.body, Op::Halt, &mut code);
+        let alpha = program
+            .alpha
+            .as_ref()
+            .map(|alpha| entry(alpha, Op::Halt, &mut code));
+        let remap = program
+            .remap
+            .as_ref()
+            .map(|y| entry(y, Op::Halt, &mut code));
+        let bindings = program
+            .bindings
+            .iter()
+            .enumerate()
+            .map(|(slot, binding)| entry(&binding.value, Op::Return(slot), &mut code))
+            .collect();
+
+        // A binding runs on top of the stack of whatever used it, and can in turn
+        // use the ones before it, so the worst case is every binding nested.
+        let entries =
+            1 + usize::from(program.alpha.is_some()) + usize::from(program.remap.is_some());
+        let max_stack =
+            depths[..entries].iter().max().unwrap() + depths[entries..].iter().sum::<usize>();
+
+        Compiled {
+            code,
+            body,
+            alpha,
+            remap,
+            bindings,
+            kernels: program.kernels.clone(),
+            max_stack,
+        }
+    }
+}
+
+fn emit(expr: &Expr, code: &mut Vec<Op>) {
+    match &expr.kind {
+        ExprKind::Num(n) => code.push(Op::Num(*n)),
+        ExprKind::Var(var) => code.push(Op::Var(*var)),
+        ExprKind::Local { slot, .. } => code.push(Op::Local(*slot)),
+        ExprKind::Convolve { slot, .. } => code.push(Op::Convolve(*slot)),
+        ExprKind::Unary { op, operand } => {
+            emit(operand, code);
+            code.push(Op::Unary(*op));
+        }
+        ExprKind::Swizzle { operand, lanes } => {
+            emit(operand, code);
+            code.push(Op::Swizzle(*lanes));
+        }
+        ExprKind::Offset { var, dx, dy } => {
+            emit(dx, code);
+            emit(dy, code);
+            code.push(Op::Offset(*var));
+        }
+        ExprKind::Binary { op, lhs, rhs } => {
+            emit(lhs, code);
+            emit(rhs, code);
+            code.push(Op::Binary(*op));
+        }
+        ExprKind::Call { func, args } => {
+            for arg in args {
+                emit(arg, code);
+            }
+            code.push(Op::Call(*func, args.len()));
+        }
+        ExprKind::Cond {
+            cond,
+            if_true,
+            if_false,
+        } => {
+            emit(cond, code);
+            let skip_true = code.len();
+            code.push(Op::SkipIfNone(0));
+            emit(if_true, code);
+            let skip_false = code.len();
+            code.push(Op::SkipIfAll(0));
+            emit(if_false, code);
+
+            // Skipping the true branch lands on the check for the false one, which
+            // then never skips since the mask is all zero.
+            code[skip_true] = Op::SkipIfNone(skip_false);
+            code[skip_false] = Op::SkipIfAll(code.len());
+            code.push(Op::Select);
+        }
+        ExprKind::Channels(items) => {
+            for item in items {
+                emit(item, code);
+            }
+            code.push(Op::Channels);
+        }
+        ExprKind::Group(inner) => emit(inner, code),
+    }
+}
+
+/// Highest stack depth reached running `code` from an empty stack. Both sides of
+/// a skip leave the same number of values, so the code can be walked in order.
+fn max_depth(code: &[Op]) -> usize {
+    let mut depth = 0usize;
+    let mut max = 0;
+    for op in code {
+        let (pops, pushes) = match op {
+            Op::Num(_) | Op::Var(_) | Op::Local(_) | Op::Convolve(_) => (0, 1),
+            Op::Return(_) | Op::Halt | Op::SkipIfNone(_) | Op::SkipIfAll(_) => (0, 0),
+            Op::Unary(_) | Op::Swizzle(_) => (1, 1),
+            Op::Binary(_) | Op::Offset(_) => (2, 1),
+            Op::Call(_, argc) => (*argc, 1),
+            Op::Channels | Op::Select => (3, 1),
+        };
+        depth = depth - pops + pushes;
+        max = max.max(depth);
+    }
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_conditional_jumps() {
+        let compiled = Compiled::new(&parse("if x ? 9 then c else 1").unwrap());
+        let expected = vec![
+            Op::Var(Var::X),
+            Op::Num(9),
+            Op::Binary(BinOp::Greater),
+            Op::SkipIfNone(5),
+            Op::Var(Var::Color),
+            Op::SkipIfAll(7),
+            Op::Num(1),
+            Op::Select,
+            Op::Halt,
+        ];
+        assert_eq!(compiled.code, expected);
+    }
+
+    #[test]
+    fn test_entries() {
+        let compiled = Compiled::new(&parse("let p = c + 1; let q = p * p; [q, p, 2, x]").unwrap());
+        assert_eq!(compiled.body, 0);
+        assert_eq!(compiled.alpha, Some(5));
+        assert_eq!(compiled.bindings, vec![7, 11]);
+        assert_eq!(compiled.code[10], Op::Return(0));
+        assert_eq!(compiled.code[14], Op::Return(1));
+        // The body holds three values, each binding adds up to two on top.
+        assert_eq!(compiled.max_stack, 3 + 2 + 2);
+    }
+}
@@ -0,0 +1,119 @@
+use image::{DynamicImage, GenericImageView, Rgba};
+
+/// What a pixel read outside of the image returns, for the neighborhood variables,
+/// offsets and remapped coordinates alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum EdgeMode {
+    /// Black.
+    #[default]
+    Zero,
+    /// The nearest pixel on the border.
+    Clamp,
+    /// The image repeats, reading past the right edge continues on the left.
+    Wrap,
+    /// The image is reflected at the border without repeating the edge pixel.
+    Mirror,
+    /// The pixel being evaluated shows through, so borders keep their own color.
+    Transparent,
+}
+
+impl EdgeMode {
+    /// Maps a coordinate on an axis of `len` pixels into the image, `None` when
+    /// it is not mapped back.
+    pub fn resolve(self, coord: i64, len: u32) -> Option<u32> {
+        let len = i64::from(len);
+        if (0..len).contains(&coord) {
+            return Some(coord as u32);
+        }
+
+        let coord = match self {
+            EdgeMode::Zero | EdgeMode::Transparent => return None,
+            EdgeMode::Clamp => coord.clamp(0, len - 1),
+            EdgeMode::Wrap => coord.rem_euclid(len),
+            EdgeMode::Mirror if len == 1 => 0,
+            EdgeMode::Mirror => {
+                let period = 2 * (len - 1);
+                let m = coord.rem_euclid(period);
+                if m < len {
+                    m
+                } else {
+                    period - m
+                }
+            }
+        };
+        Some(coord as u32)
+    }
+
+    /// Reads the pixel at `(x, y)`, `None` when the edge is transparent and the
+    /// pixel being evaluated should be used instead.
+    pub fn read(self, input: &DynamicImage, (x, y): (i64, i64)) -> Option<Rgba<u8>> {
+        let (width, height) = input.dimensions();
+        match (self.resolve(x, width), self.resolve(y, height)) {
+            (Some(x), Some(y)) => Some(input.get_pixel(x, y)),
+            _ if self == EdgeMode::Transparent => None,
+            _ => Some(Rgba([0; 4])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_all(mode: EdgeMode, coords: &[i64], len: u32) -> Vec<Option<u32>> {
+        coords.iter().map(|c| mode.resolve(*c, len)).collect()
+    }
+
+    #[test]
+    fn test_inside_is_unchanged() {
+        for mode in [
+            EdgeMode::Zero,
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+            EdgeMode::Transparent,
+        ] {
+            assert_eq!(
+                resolve_all(mode, &[0, 1, 3], 4),
+                [Some(0), Some(1), Some(3)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_outside() {
+        let coords = [-5, -1, 4, 6];
+        assert_eq!(resolve_all(EdgeMode::Zero, &coords, 4), [None; 4]);
+        assert_eq!(
+            resolve_all(EdgeMode::Clamp, &coords, 4),
+            [Some(0), Some(0), Some(3), Some(3)]
+        );
+        assert_eq!(
+            resolve_all(EdgeMode::Wrap, &coords, 4),
+            [Some(3), Some(3), Some(0), Some(2)]
+        );
+        assert_eq!(
+            resolve_all(EdgeMode::Mirror, &coords, 4),
+            [Some(1), Some(1), Some(2), Some(0)]
+        );
+    }
+
+    #[test]
+    fn test_read() {
+        let img = DynamicImage::from(image::RgbaImage::from_pixel(2, 2, Rgba([9, 8, 7, 6])));
+        let read = |mode: EdgeMode, p| mode.read(&img, p).map(|p| p.0);
+        assert_eq!(read(EdgeMode::Zero, (1, 1)), Some([9, 8, 7, 6]));
+        assert_eq!(read(EdgeMode::Zero, (2, 1)), Some([0; 4]));
+        assert_eq!(read(EdgeMode::Clamp, (1, -4)), Some([9, 8, 7, 6]));
+        assert_eq!(read(EdgeMode::Transparent, (0, 1)), Some([9, 8, 7, 6]));
+        assert_eq!(read(EdgeMode::Transparent, (-1, 1)), None);
+    }
+
+    #[test]
+    fn test_mirror_single_pixel() {
+        assert_eq!(
+            resolve_all(EdgeMode::Mirror, &[-3, 2], 1),
+            [Some(0), Some(0)]
+        );
+    }
+}
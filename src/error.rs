@@ -0,0 +1,212 @@
+use std::fmt;
+
+use crate::ast::{Func, Span};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// An identifier that does not name any variable.
+    UnknownVariable(String),
+    /// A call to a function that does not exist.
+    UnknownFunction(String),
+    /// A function called with the wrong number of arguments.
+    ArgumentCount { func: Func, found: usize },
+    /// A `let` binding or `kernel` using the name of a variable, function or
+    /// keyword.
+    ReservedName(String),
+    /// A `kernel` named like an earlier binding or kernel, or a binding named like
+    /// a kernel.
+    DuplicateName(String),
+    /// A `kernel` declaration whose weights can not be used, e.g. rows of
+    /// different lengths.
+    InvalidKernel(&'static str),
+    /// A keyword that had to follow, e.g. `then` after the condition of an `if`.
+    Expected(&'static str),
+    /// A token that can not appear at this point, e.g. a `,` outside of a call.
+    UnexpectedToken(String),
+    /// A character that is not part of the expression language.
+    InvalidCharacter(char),
+    /// A number literal larger than 255.
+    NumberOutOfRange,
+    /// A `(` without its `)` or the other way around.
+    UnbalancedParen,
+    /// A `[` without its `]` or the other way around.
+    UnbalancedBracket,
+    /// A channel list without 3 or 4 components.
+    ChannelCount(usize),
+    /// A `[r, g, b, a]` list that is not the whole expression.
+    NestedAlpha,
+    /// A swizzle that is not one or three of `r`, `g` and `b`.
+    InvalidSwizzle(String),
+    /// An offset like `x[1, 0]` on a variable that is not read from the image.
+    NotSpatial(String),
+    /// An offset without exactly 2 components.
+    OffsetCount(usize),
+    /// An operator missing its left or right operand.
+    DanglingOperator,
+    /// Two operands next to each other, e.g. `c c`.
+    MissingOperator,
+    /// Nothing to evaluate, either the whole input or a `()` group.
+    EmptyExpression,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownVariable(name) => write!(f, "unknown variable '{}'", name),
+            ParseErrorKind::UnknownFunction(name) => write!(f, "unknown function '{}'", name),
+            ParseErrorKind::ArgumentCount { func, found } => {
+                let noun = |n: usize| if n == 1 { "argument" } else { "arguments" };
+                match func.arity() {
+                    (min, Some(max)) if min == max => write!(
+                        f,
+                        "{} takes {} {}, found {}",
+                        func.name(),
+                        min,
+                        noun(min),
+                        found
+                    ),
+                    (min, Some(max)) => write!(
+                        f,
+                        "{} takes {} to {} arguments, found {}",
+                        func.name(),
+                        min,
+                        max,
+                        found
+                    ),
+                    (min, None) => write!(
+                        f,
+                        "{} takes at least {} {}, found {}",
+                        func.name(),
+                        min,
+                        noun(min),
+                        found
+                    ),
+                }
+            }
+            ParseErrorKind::ReservedName(name) => {
+                write!(f, "'{}' is a built-in name and can not be bound", name)
+            }
+            ParseErrorKind::DuplicateName(name) => write!(f, "'{}' is already declared", name),
+            ParseErrorKind::InvalidKernel(what) => write!(f, "invalid kernel, {}", what),
+            ParseErrorKind::Expected(what) => write!(f, "expected '{}'", what),
+            ParseErrorKind::UnexpectedToken(tok) => write!(f, "unexpected '{}'", tok),
+            ParseErrorKind::InvalidCharacter(c) => write!(f, "invalid character '{}'", c),
+            ParseErrorKind::NumberOutOfRange => write!(f, "number exceeds 255"),
+            ParseErrorKind::UnbalancedParen => write!(f, "unbalanced parenthesis"),
+            ParseErrorKind::UnbalancedBracket => write!(f, "unbalanced bracket"),
+            ParseErrorKind::ChannelCount(found) => {
+                write!(f, "channel lists take 3 or 4 components, found {}", found)
+            }
+            ParseErrorKind::NestedAlpha => write!(
+                f,
+                "alpha can only be set by a channel list that is the whole expression"
+            ),
+            ParseErrorKind::InvalidSwizzle(text) => write!(
+                f,
+                "invalid swizzle '{}', expected one or three of r, g and b",
+                text
+            ),
+            ParseErrorKind::NotSpatial(name) => {
+                write!(f, "'{}' is not read from the image and has no offset", name)
+            }
+            ParseErrorKind::OffsetCount(found) => {
+                write!(f, "offsets take 2 components, found {}", found)
+            }
+            ParseErrorKind::DanglingOperator => write!(f, "operator is missing an operand"),
+            ParseErrorKind::MissingOperator => write!(f, "expected an operator between operands"),
+            ParseErrorKind::EmptyExpression => write!(f, "empty expression"),
+        }
+    }
+}
+
+/// An error found while parsing an expression, pointing at the offending bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Span) -> Self {
+        ParseError { kind, span }
+    }
+
+    /// Renders the error with the source line and a caret under the span:
+    ///
+    /// ```text
+    /// unknown variable 'q' at 1:5
+    ///   |
+    /// 1 | c + q * 2
+    ///   |     ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let end = self.span.end.clamp(start, source.len());
+
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let line_no = source[..line_start].matches('\n').count() + 1;
+
+        let column = source[line_start..start].chars().count();
+        let width = source[start..end.min(line_end)].chars().count().max(1);
+
+        let gutter = " ".repeat(line_no.to_string().len());
+        format!(
+            "{} at {}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.kind,
+            line_no,
+            column + 1,
+            gutter,
+            line_no,
+            line,
+            gutter,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at bytes {}..{}",
+            self.kind, self.span.start, self.span.end
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_caret() {
+        let err = ParseError::new(
+            ParseErrorKind::UnknownVariable("q".to_string()),
+            Span::new(4, 5),
+        );
+        let expected = "unknown variable 'q' at 1:5\n  |\n1 | c + q * 2\n  |     ^";
+        assert_eq!(err.render("c + q * 2"), expected);
+    }
+
+    #[test]
+    fn test_render_multiline() {
+        let source = "c +\n  300";
+        let err = ParseError::new(ParseErrorKind::NumberOutOfRange, Span::new(6, 9));
+        let expected = "number exceeds 255 at 2:3\n  |\n2 |   300\n  |   ^^^";
+        assert_eq!(err.render(source), expected);
+    }
+
+    #[test]
+    fn test_render_end_of_input() {
+        let err = ParseError::new(ParseErrorKind::DanglingOperator, Span::new(3, 3));
+        let expected = "operator is missing an operand at 1:4\n  |\n1 | c +\n  |    ^";
+        assert_eq!(err.render("c +"), expected);
+    }
+}
@@ -1,8 +1,12 @@
 use image::{DynamicImage, GenericImageView, Rgba};
-use rand::prelude::ThreadRng;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
-use crate::parser::Token;
+use crate::ast::{BinOp, Func, UnOp, Var};
+use crate::compile::{Compiled, Op};
+use crate::edge::EdgeMode;
+use crate::kernels::Kernels;
+use crate::noise::Field;
 
 #[derive(Debug, Clone, Copy)]
 struct RgbSum {
@@ -11,600 +15,861 @@ struct RgbSum {
     b: u8,
 }
 
-#[derive(Debug)]
-struct SumSave {
-    v_y: Option<RgbSum>,
-    v_b: Option<RgbSum>,
-    v_e: Option<RgbSum>,
-    v_r: Option<RgbSum>,
-    v_h: Option<RgbSum>,
-    v_v: Option<RgbSum>,
-    v_d: Option<RgbSum>,
-    v_high: Option<RgbSum>,
-    v_low: Option<RgbSum>,
-}
+impl RgbSum {
+    fn splat(v: u8) -> Self {
+        RgbSum { r: v, g: v, b: v }
+    }
+
+    fn map(self, f: impl Fn(u8) -> u8) -> RgbSum {
+        RgbSum {
+            r: f(self.r),
+            g: f(self.g),
+            b: f(self.b),
+        }
+    }
+
+    fn lanes(self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    /// Component `i` of the result is component `lanes[i]` of `self`.
+    fn swizzle(self, lanes: [u8; 3]) -> RgbSum {
+        let v = self.lanes();
+        RgbSum {
+            r: v[lanes[0] as usize],
+            g: v[lanes[1] as usize],
+            b: v[lanes[2] as usize],
+        }
+    }
 
-impl SumSave {
-    fn new() -> Self {
-        SumSave {
-            v_y: None,
-            v_b: None,
-            v_e: None,
-            v_r: None,
-            v_h: None,
-            v_v: None,
-            v_d: None,
-            v_high: None,
-            v_low: None,
+    /// Takes each component from `a` where `mask` is non zero and from `b` otherwise.
+    fn select(mask: RgbSum, a: RgbSum, b: RgbSum) -> RgbSum {
+        let pick = |m: u8, a: u8, b: u8| if m != 0 { a } else { b };
+        RgbSum {
+            r: pick(mask.r, a.r, b.r),
+            g: pick(mask.g, a.g, b.g),
+            b: pick(mask.b, a.b, b.b),
+        }
+    }
+
+    fn zip_with(self, other: RgbSum, f: impl Fn(u8, u8) -> u8) -> RgbSum {
+        RgbSum {
+            r: f(self.r, other.r),
+            g: f(self.g, other.g),
+            b: f(self.b, other.b),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct EvalContext {
-    pub tokens: Vec<Token>,
+#[derive(Debug)]
+pub struct EvalContext<'a> {
+    pub program: &'a Compiled,
+    /// Buffers reused from pixel to pixel, made for `program`.
+    pub machine: &'a mut Machine,
     pub size: (u32, u32),
     pub rgba: [u8; 4],
     pub saved_rgb: [u8; 3],
     pub position: (u32, u32),
+    /// Used by offsets like `c[dx, dy]` reaching outside of the image.
+    pub edge: EdgeMode,
+    /// Shared by every pixel of the image, for `blur(r)` and the like.
+    pub kernels: &'a Kernels<'a>,
+    /// Drawn from by `N` and `r`, seeded once so a run can be reproduced.
+    pub rng: &'a mut ChaCha8Rng,
+    /// Seed of the noise functions, which unlike `rng` do not depend on the order
+    /// pixels are evaluated in.
+    pub seed: u64,
+    /// Index of the frame in an animation, 0 for still images.
+    pub frame: u32,
 }
 
-pub fn eval(
-    ctx: EvalContext,
-    input: &DynamicImage,
-    mut rng: ThreadRng,
-) -> Result<Rgba<u8>, String> {
-    let EvalContext {
-        tokens,
-        size,
-        rgba,
-        saved_rgb,
-        position,
-    } = ctx;
-    let (width, height) = size;
-    let (x, y) = position;
-    let [r, g, b, a] = rgba;
-    let [sr, sg, sb] = saved_rgb;
+#[cfg(test)]
+impl<'a> EvalContext<'a> {
+    /// The context of the pixel of `input` at `position`, with the edge mode of
+    /// `kernels`, `s` at zero and seed and frame 0. Tests set the other fields
+    /// with struct update syntax.
+    pub fn for_test(
+        program: &'a Compiled,
+        machine: &'a mut Machine,
+        kernels: &'a Kernels<'a>,
+        rng: &'a mut ChaCha8Rng,
+        input: &DynamicImage,
+        position: (u32, u32),
+    ) -> Self {
+        EvalContext {
+            program,
+            machine,
+            size: input.dimensions(),
+            rgba: input.get_pixel(position.0, position.1).0,
+            saved_rgb: [0; 3],
+            position,
+            edge: kernels.edge(),
+            kernels,
+            rng,
+            seed: 0,
+            frame: 0,
+        }
+    }
+}
 
+pub fn eval(ctx: EvalContext, input: &DynamicImage) -> Result<Rgba<u8>, String> {
+    let a = ctx.rgba[3];
     if a == 0 {
         return Ok(Rgba([0, 0, 0, 0]));
     }
 
-    let mut stack: Vec<RgbSum> = Vec::with_capacity(tokens.len());
-
-    let div = |a: u8, b: u8| -> u8 {
-        if b == 0 {
-            return a;
-        }
-        a.wrapping_div(b)
+    let program = ctx.program;
+    let (mut pixel, machine) = Pixel::new(ctx, input);
+    let col = machine.run(program, program.body, &mut pixel);
+    let a = match program.alpha {
+        Some(alpha) => machine.run(program, alpha, &mut pixel).r,
+        None => a,
     };
+    Ok(Rgba([col.r, col.g, col.b, a]))
+}
+
+/// Evaluates a program of the form `x, y`, giving the normalized coordinate the
+/// pixel is read from. Unlike [`eval`] transparent pixels are evaluated too, they
+/// can still pull in a visible one.
+pub fn eval_coords(ctx: EvalContext, input: &DynamicImage) -> Result<(u8, u8), String> {
+    let program = ctx.program;
+    let y = program
+        .remap
+        .ok_or("expression does not give a coordinate")?;
+
+    let (mut pixel, machine) = Pixel::new(ctx, input);
+    let x = machine.run(program, program.body, &mut pixel).r;
+    let y = machine.run(program, y, &mut pixel).r;
+    Ok((x, y))
+}
+
+/// The stack and binding slots of a [`Compiled`] program. Made once and reused
+/// for every pixel, so evaluating a pixel does not allocate.
+#[derive(Debug)]
+pub struct Machine {
+    stack: Vec<RgbSum>,
+    slots: Vec<Option<RgbSum>>,
+    /// Where to continue once a binding used for the first time is computed.
+    returns: Vec<usize>,
+}
 
-    let modu = |a: u8, b: u8| -> u8 {
-        if b == 0 {
-            return a;
+impl Machine {
+    pub fn new(program: &Compiled) -> Self {
+        Machine {
+            stack: Vec::with_capacity(program.max_stack),
+            slots: vec![None; program.bindings.len()],
+            returns: Vec::with_capacity(program.bindings.len()),
         }
-        a.wrapping_rem(b)
-    };
+    }
 
-    let bit_and_not = |a: u8, b: u8| -> u8 { a & !b };
+    /// Forgets the bindings computed for the previous pixel.
+    fn reset(&mut self) {
+        self.slots.fill(None);
+    }
 
-    let weight = |a: u8, b: u8| -> u8 {
-        let fuzz = f64::from(b) / 255.0;
-        let r = f64::from(a) * fuzz;
-        r as u8
-    };
+    /// Runs the code at `entry` up to its [`Op::Halt`]. Validation made sure every
+    /// operation finds its operands, so the stack is never short.
+    fn run(&mut self, program: &Compiled, entry: usize, pixel: &mut Pixel) -> RgbSum {
+        let stack = &mut self.stack;
+        let mut pc = entry;
+        loop {
+            let op = program.code[pc];
+            pc += 1;
+            match op {
+                Op::Num(n) => stack.push(RgbSum::splat(n)),
+                Op::Var(var) => stack.push(pixel.var(var)),
+                Op::Convolve(slot) => {
+                    let [r, g, b] = pixel.kernels.convolved(slot, pixel.position);
+                    stack.push(RgbSum { r, g, b });
+                }
+                Op::Local(slot) => match self.slots[slot] {
+                    Some(v) => stack.push(v),
+                    None => {
+                        // Computed on first use, so a binding only used in an
+                        // untaken branch costs nothing.
+                        self.returns.push(pc);
+                        pc = program.bindings[slot];
+                    }
+                },
+                Op::Return(slot) => {
+                    self.slots[slot] = stack.last().copied();
+                    pc = self.returns.pop().unwrap();
+                }
+                Op::Unary(op) => {
+                    let v = stack.pop().unwrap();
+                    stack.push(unary(op, v));
+                }
+                Op::Swizzle(lanes) => {
+                    let v = stack.pop().unwrap();
+                    stack.push(v.swizzle(lanes));
+                }
+                Op::Binary(op) => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    stack.push(binary(op, a, b));
+                }
+                Op::Call(func, argc) => {
+                    let args = &stack[stack.len() - argc..];
+                    let v = match func {
+                        _ if func.is_noise() => pixel.noise(func, args),
+                        _ if func.is_kernel() => pixel.kernel(func, args),
+                        _ => call(func, args),
+                    };
+                    stack.truncate(stack.len() - argc);
+                    stack.push(v);
+                }
+                Op::Offset(var) => {
+                    let dy = stack.pop().unwrap().r as i8;
+                    let dx = stack.pop().unwrap().r as i8;
+                    stack.push(pixel.sample(var, dx, dy));
+                }
+                Op::Channels => {
+                    let b = stack.pop().unwrap().b;
+                    let g = stack.pop().unwrap().g;
+                    let r = stack.pop().unwrap().r;
+                    stack.push(RgbSum { r, g, b });
+                }
+                // Only the branches some component selects are evaluated, so `N`
+                // and `r` in an unused branch do not consume random numbers.
+                Op::SkipIfNone(target) => {
+                    let mask = stack[stack.len() - 1];
+                    if mask.lanes().iter().all(|v| *v == 0) {
+                        stack.push(RgbSum::splat(0));
+                        pc = target;
+                    }
+                }
+                Op::SkipIfAll(target) => {
+                    let mask = stack[stack.len() - 2];
+                    if mask.lanes().iter().all(|v| *v != 0) {
+                        stack.push(RgbSum::splat(0));
+                        pc = target;
+                    }
+                }
+                Op::Select => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    let mask = stack.pop().unwrap();
+                    stack.push(RgbSum::select(mask, a, b));
+                }
+                Op::Halt => return stack.pop().unwrap(),
+            }
+        }
+    }
+}
 
-    let three_rule = |x: u32, max: u32| -> u8 { (((255 * x) / max) & 255) as u8 };
+/// Reads variables, offsets and noise at any pixel, for evaluators that run the
+/// operators over many pixels at once. Only meant for programs without `s`, `N`
+/// and `r`, which depend on the order pixels are visited in.
+pub struct Inputs<'a> {
+    input: &'a DynamicImage,
+    edge: EdgeMode,
+    kernels: &'a Kernels<'a>,
+    seed: u64,
+    frame: u32,
+    /// Never drawn from, a [`Pixel`] needs one.
+    rng: ChaCha8Rng,
+}
 
-    let is_in_bounds =
-        |x: u32, y: u32, width: u32, height: u32| -> bool { x < width && y < height };
+impl<'a> Inputs<'a> {
+    pub fn new(
+        input: &'a DynamicImage,
+        edge: EdgeMode,
+        kernels: &'a Kernels<'a>,
+        seed: u64,
+        frame: u32,
+    ) -> Self {
+        Inputs {
+            input,
+            edge,
+            kernels,
+            seed,
+            frame,
+            rng: ChaCha8Rng::seed_from_u64(0),
+        }
+    }
 
-    let mut saved = SumSave::new();
+    fn pixel(&mut self, position: (u32, u32)) -> Pixel<'_> {
+        let [r, g, b] = self.kernels.var(Var::Color, position);
+        Pixel {
+            input: self.input,
+            rng: &mut self.rng,
+            size: self.input.dimensions(),
+            position,
+            rgb: RgbSum { r, g, b },
+            saved_rgb: [0; 3],
+            rand: None,
+            edge: self.edge,
+            kernels: self.kernels,
+            seed: self.seed,
+            frame: self.frame,
+        }
+    }
 
-    for tok in tokens {
-        match tok {
-            Token::Num(n) => stack.push(RgbSum { r: n, g: n, b: n }),
+    pub fn var(&mut self, var: Var, position: (u32, u32)) -> [u8; 3] {
+        match var.is_spatial() {
+            true => self.kernels.var(var, position),
+            false => self.pixel(position).var(var).lanes(),
+        }
+    }
 
-            Token::Add => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r.wrapping_add(b.r),
-                    g: a.g.wrapping_add(b.g),
-                    b: a.b.wrapping_add(b.b),
-                });
-            }
+    pub fn offset(&mut self, var: Var, position: (u32, u32), dx: i8, dy: i8) -> [u8; 3] {
+        self.pixel(position).sample(var, dx, dy).lanes()
+    }
 
-            Token::Sub => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r.wrapping_sub(b.r),
-                    g: a.g.wrapping_sub(b.g),
-                    b: a.b.wrapping_sub(b.b),
-                });
-            }
+    pub fn noise(&mut self, func: Func, position: (u32, u32), arg: Option<[u8; 3]>) -> [u8; 3] {
+        let arg = arg.map(|[r, g, b]| RgbSum { r, g, b });
+        self.pixel(position).noise(func, arg.as_slice()).lanes()
+    }
 
-            Token::Mul => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r.wrapping_mul(b.r),
-                    g: a.g.wrapping_mul(b.g),
-                    b: a.b.wrapping_mul(b.b),
-                });
-            }
+    pub fn kernel(&self, func: Func, position: (u32, u32), args: &[[u8; 3]]) -> [u8; 3] {
+        self.kernels.at(func, args, position)
+    }
 
-            Token::Div => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: div(a.r, b.r),
-                    g: div(a.g, b.g),
-                    b: div(a.b, b.b),
-                });
-            }
+    pub fn convolved(&self, slot: usize, position: (u32, u32)) -> [u8; 3] {
+        self.kernels.convolved(slot, position)
+    }
+}
 
-            Token::Mod => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: modu(a.r, b.r),
-                    g: modu(a.g, b.g),
-                    b: modu(a.b, b.b),
-                });
-            }
+/// State for evaluating an expression at a single pixel. Variables read from the
+/// image are looked up in the planes of `kernels`.
+struct Pixel<'a> {
+    input: &'a DynamicImage,
+    rng: &'a mut ChaCha8Rng,
+    size: (u32, u32),
+    position: (u32, u32),
+    rgb: RgbSum,
+    saved_rgb: [u8; 3],
+    /// `r` of this pixel, drawn once so every use of it gives the same value.
+    rand: Option<RgbSum>,
+    edge: EdgeMode,
+    kernels: &'a Kernels<'a>,
+    seed: u64,
+    frame: u32,
+}
 
-            Token::Pow => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r.wrapping_pow(b.r.into()),
-                    g: a.g.wrapping_pow(b.g.into()),
-                    b: a.b.wrapping_pow(b.b.into()),
-                });
-            }
+impl<'a> Pixel<'a> {
+    /// Splits the context into the pixel and the machine, ready to run.
+    fn new(ctx: EvalContext<'a>, input: &'a DynamicImage) -> (Self, &'a mut Machine) {
+        let EvalContext {
+            program: _,
+            machine,
+            size,
+            rgba: [r, g, b, _],
+            saved_rgb,
+            position,
+            edge,
+            kernels,
+            rng,
+            seed,
+            frame,
+        } = ctx;
+        machine.reset();
+
+        let pixel = Pixel {
+            input,
+            rng,
+            size,
+            position,
+            rgb: RgbSum { r, g, b },
+            saved_rgb,
+            rand: None,
+            edge,
+            kernels,
+            seed,
+            frame,
+        };
+        (pixel, machine)
+    }
 
-            Token::BitAnd => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r & b.r,
-                    g: a.g & b.g,
-                    b: a.b & b.b,
-                });
-            }
+    /// Evaluates `var` as if the current pixel was moved by `(dx, dy)`, a position
+    /// outside of the image is mapped back by the edge mode.
+    fn sample(&mut self, var: Var, dx: i8, dy: i8) -> RgbSum {
+        let (width, height) = self.size;
+        let (x, y) = self.position;
+        let x = self.edge.resolve(i64::from(x) + i64::from(dx), width);
+        let y = self.edge.resolve(i64::from(y) + i64::from(dy), height);
+        let (Some(x), Some(y)) = (x, y) else {
+            return match self.edge {
+                EdgeMode::Transparent => self.var(var),
+                _ => RgbSum::splat(0),
+            };
+        };
+
+        let [r, g, b] = self.kernels.var(Var::Color, (x, y));
+        let mut moved = Pixel {
+            input: self.input,
+            rng: &mut *self.rng,
+            size: self.size,
+            position: (x, y),
+            rgb: RgbSum { r, g, b },
+            saved_rgb: self.saved_rgb,
+            rand: None,
+            edge: self.edge,
+            kernels: self.kernels,
+            seed: self.seed,
+            frame: self.frame,
+        };
+        moved.var(var)
+    }
 
-            Token::BitOr => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r | b.r,
-                    g: a.g | b.g,
-                    b: a.b | b.b,
-                });
+    /// Each component is a separate field, so the noise is colored unless it is
+    /// swizzled to a single one.
+    fn noise(&self, func: Func, args: &[RgbSum]) -> RgbSum {
+        let (x, y) = self.position;
+        let p = [i64::from(x), i64::from(y), i64::from(self.frame)];
+        let arg = args.first().map_or([0; 3], |v| [v.r, v.g, v.b]);
+
+        let lane = |i: usize| {
+            let field = |salt: u8| Field::new(self.seed, &[func as u64, i as u64, salt.into()]);
+            match func {
+                Func::Hash => field(arg[i]).white(p),
+                Func::ValueNoise => field(0).value(p, arg[i]),
+                _ => field(0).perlin(p, arg[i]),
             }
+        };
+        RgbSum {
+            r: lane(0),
+            g: lane(1),
+            b: lane(2),
+        }
+    }
 
-            Token::BitAndNot => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: bit_and_not(a.r, b.r),
-                    g: bit_and_not(a.g, b.g),
-                    b: bit_and_not(a.b, b.b),
-                });
-            }
+    fn kernel(&self, func: Func, args: &[RgbSum]) -> RgbSum {
+        // Kernels take at most two arguments.
+        let mut lanes = [[0; 3]; 2];
+        for (lane, arg) in lanes.iter_mut().zip(args) {
+            *lane = arg.lanes();
+        }
+        let [r, g, b] = self.kernels.at(func, &lanes[..args.len()], self.position);
+        RgbSum { r, g, b }
+    }
+
+    fn var(&mut self, var: Var) -> RgbSum {
+        let input = self.input;
+        let (width, height) = self.size;
+        let (x, y) = self.position;
+        let RgbSum { r, g, b } = self.rgb;
+        let [sr, sg, sb] = self.saved_rgb;
+        let rng = &mut *self.rng;
+        let edge = self.edge;
+
+        match var {
+            Var::Color => RgbSum { r, g, b },
+            Var::Red => RgbSum { r: 255, g: 0, b: 0 },
+            Var::Green => RgbSum { r: 0, g: 255, b: 0 },
+            Var::Blue => RgbSum { r: 0, g: 0, b: 255 },
+            Var::Saved => RgbSum {
+                r: sr,
+                g: sg,
+                b: sb,
+            },
+            Var::X => RgbSum::splat(three_rule(x, width)),
+            Var::Frame => RgbSum::splat(self.frame as u8),
+            Var::Y => RgbSum::splat(three_rule(y, height)),
+            Var::Rand => match self.rand {
+                Some(v_r) => v_r,
+                None => {
+                    let x1 = rng.gen_range(0..=2) as u32;
+                    let y1 = rng.gen_range(0..=2) as u32;
+
+                    let x2 = rng.gen_range(0..=2) as u32;
+                    let y2 = rng.gen_range(0..=2) as u32;
+
+                    let x3 = rng.gen_range(0..=2) as u32;
+                    let y3 = rng.gen_range(0..=2) as u32;
+
+                    let read = |dx: u32, dy: u32| {
+                        let p = (i64::from(x + dx), i64::from(y + dy));
+                        edge.read(input, p).map_or([r, g, b, 0], |p| p.0)
+                    };
+                    let p1 = read(x1, y1);
+                    let p2 = read(x2, y2);
+                    let p3 = read(x3, y3);
+
+                    let v_r = RgbSum {
+                        r: p1[0],
+                        g: p2[1],
+                        b: p3[2],
+                    };
 
-            Token::BitXor => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-                stack.push(RgbSum {
-                    r: a.r ^ b.r,
-                    g: a.g ^ b.g,
-                    b: a.b ^ b.b,
-                });
+                    self.rand = Some(v_r);
+                    v_r
+                }
+            },
+            Var::Noise => RgbSum {
+                r: rng.gen_range(0..=255),
+                g: rng.gen_range(0..=255),
+                b: rng.gen_range(0..=255),
+            },
+            Var::Lum
+            | Var::Blur
+            | Var::Edge
+            | Var::High
+            | Var::Low
+            | Var::HFlip
+            | Var::VFlip
+            | Var::DFlip => {
+                let [r, g, b] = self.kernels.var(var, self.position);
+                RgbSum { r, g, b }
             }
+        }
+    }
+}
 
-            Token::BitLShift => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
+/// Applies a prefix operator to a constant, with the same result as evaluating it.
+pub(crate) fn fold_unary(op: UnOp, v: u8) -> u8 {
+    unary(op, RgbSum::splat(v)).r
+}
 
-                let rr = a.r.wrapping_shl(b.r.into());
-                let gg = a.g.wrapping_shl(b.g.into());
-                let bb = a.b.wrapping_shl(b.b.into());
+/// Applies an operator to constants, with the same result as evaluating it.
+pub(crate) fn fold_binary(op: BinOp, a: u8, b: u8) -> u8 {
+    binary(op, RgbSum::splat(a), RgbSum::splat(b)).r
+}
 
-                stack.push(RgbSum {
-                    r: rr,
-                    g: gg,
-                    b: bb,
-                });
-            }
+/// Calls a function that does not depend on the pixel with constant arguments.
+pub(crate) fn fold_call(func: Func, args: &[u8]) -> u8 {
+    let args: Vec<RgbSum> = args.iter().map(|v| RgbSum::splat(*v)).collect();
+    call(func, &args).r
+}
 
-            Token::BitRShift => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
+fn unary(op: UnOp, v: RgbSum) -> RgbSum {
+    match op {
+        UnOp::Neg => v.map(u8::wrapping_neg),
+        UnOp::Not => v.map(|v| !v),
+        UnOp::Abs => v.map(|v| (v as i8).unsigned_abs()),
+    }
+}
 
-                let rr = a.r.wrapping_shr(b.r.into());
-                let gg = a.g.wrapping_shr(b.g.into());
-                let bb = a.b.wrapping_shr(b.b.into());
+fn binary(op: BinOp, a: RgbSum, b: RgbSum) -> RgbSum {
+    match op {
+        BinOp::Add => a.zip_with(b, u8::wrapping_add),
+        BinOp::Sub => a.zip_with(b, u8::wrapping_sub),
+        BinOp::Mul => a.zip_with(b, u8::wrapping_mul),
+        BinOp::Div => a.zip_with(b, div),
+        BinOp::Mod => a.zip_with(b, modu),
+        BinOp::Pow => a.zip_with(b, |a, b| a.wrapping_pow(b.into())),
+        BinOp::BitAnd => a.zip_with(b, |a, b| a & b),
+        BinOp::BitOr => a.zip_with(b, |a, b| a | b),
+        BinOp::BitAndNot => a.zip_with(b, bit_and_not),
+        BinOp::BitXor => a.zip_with(b, |a, b| a ^ b),
+        BinOp::BitLShift => a.zip_with(b, |a, b| a.wrapping_shl(b.into())),
+        BinOp::BitRShift => a.zip_with(b, |a, b| a.wrapping_shr(b.into())),
+        BinOp::Weight => a.zip_with(b, weight),
+        BinOp::Greater | BinOp::Gt => a.zip_with(b, |a, b| mask(a > b)),
+        BinOp::Lt => a.zip_with(b, |a, b| mask(a < b)),
+        BinOp::Le => a.zip_with(b, |a, b| mask(a <= b)),
+        BinOp::Eq => a.zip_with(b, |a, b| mask(a == b)),
+        BinOp::Ne => a.zip_with(b, |a, b| mask(a != b)),
+        BinOp::Ge => a.zip_with(b, |a, b| mask(a >= b)),
+    }
+}
 
-                stack.push(RgbSum {
-                    r: rr,
-                    g: gg,
-                    b: bb,
-                });
+fn call(func: Func, args: &[RgbSum]) -> RgbSum {
+    match func {
+        Func::Min => fold(args, u8::min),
+        Func::Max => fold(args, u8::max),
+        Func::Clamp => args[0]
+            .zip_with(args[1], u8::max)
+            .zip_with(args[2], u8::min),
+        Func::Avg => {
+            let n = args.len() as u32;
+            let sum = |lane: fn(&RgbSum) -> u8| args.iter().map(|v| lane(v) as u32).sum::<u32>();
+            RgbSum {
+                r: (sum(|v| v.r) / n) as u8,
+                g: (sum(|v| v.g) / n) as u8,
+                b: (sum(|v| v.b) / n) as u8,
+            }
+        }
+        Func::Lerp => {
+            let [a, b, t] = [args[0], args[1], args[2]];
+            RgbSum {
+                r: lerp(a.r, b.r, t.r),
+                g: lerp(a.g, b.g, t.g),
+                b: lerp(a.b, b.b, t.b),
             }
+        }
+        Func::Hash | Func::ValueNoise | Func::Perlin => {
+            unreachable!("noise depends on the pixel and is evaluated by Pixel::noise")
+        }
+        Func::Blur
+        | Func::Edge
+        | Func::High
+        | Func::Low
+        | Func::Gauss
+        | Func::Sobel
+        | Func::Angle
+        | Func::Laplace
+        | Func::Unsharp => {
+            unreachable!("kernels depend on the pixel and are evaluated by Pixel::kernel")
+        }
+    }
+}
 
-            Token::Weight => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
+fn fold(args: &[RgbSum], f: fn(u8, u8) -> u8) -> RgbSum {
+    args[1..].iter().fold(args[0], |acc, v| acc.zip_with(*v, f))
+}
 
-                stack.push(RgbSum {
-                    r: weight(a.r, b.r),
-                    g: weight(a.g, b.g),
-                    b: weight(a.b, b.b),
-                });
-            }
+pub(crate) fn lerp(a: u8, b: u8, t: u8) -> u8 {
+    let (a, b, t) = (a as u32, b as u32, t as u32);
+    ((a * (255 - t) + b * t) / 255) as u8
+}
 
-            Token::Greater => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
+pub(crate) fn mask(cond: bool) -> u8 {
+    if cond {
+        255
+    } else {
+        0
+    }
+}
 
-                stack.push(RgbSum {
-                    r: if a.r > b.r { 255 } else { 0 },
-                    g: if a.g > b.g { 255 } else { 0 },
-                    b: if a.b > b.b { 255 } else { 0 },
-                });
-            }
+pub(crate) fn div(a: u8, b: u8) -> u8 {
+    if b == 0 {
+        return a;
+    }
+    a.wrapping_div(b)
+}
 
-            Token::Char(c) => match c {
-                'c' => stack.push(RgbSum { r, g, b }),
-                'R' => stack.push(RgbSum { r: 255, g: 0, b: 0 }),
-                'G' => stack.push(RgbSum { r: 0, g: 255, b: 0 }),
-                'B' => stack.push(RgbSum { r: 0, g: 0, b: 255 }),
-                'Y' => {
-                    let v_y = match saved.v_y {
-                        Some(v_y) => v_y,
-                        None => {
-                            let y =
-                                f64::from(r) * 0.299 + f64::from(g) * 0.587 + f64::from(b) * 0.0722;
-                            let v_y = RgbSum {
-                                r: y as u8,
-                                g: y as u8,
-                                b: y as u8,
-                            };
-                            saved.v_y = Some(v_y);
-                            v_y
-                        }
-                    };
+pub(crate) fn modu(a: u8, b: u8) -> u8 {
+    if b == 0 {
+        return a;
+    }
+    a.wrapping_rem(b)
+}
 
-                    stack.push(v_y);
-                }
-                's' => stack.push(RgbSum {
-                    r: sr,
-                    g: sg,
-                    b: sb,
-                }),
-                'x' => {
-                    let xu = three_rule(x, width);
-                    stack.push(RgbSum {
-                        r: xu,
-                        g: xu,
-                        b: xu,
-                    });
-                }
-                'y' => {
-                    let yu = three_rule(y, height);
-                    stack.push(RgbSum {
-                        r: yu,
-                        g: yu,
-                        b: yu,
-                    });
-                }
-                'r' => {
-                    let v_r = match saved.v_r {
-                        Some(v_r) => v_r,
-                        None => {
-                            let x1 = rng.gen_range(0..=2) as u32;
-                            let y1 = rng.gen_range(0..=2) as u32;
-
-                            let x2 = rng.gen_range(0..=2) as u32;
-                            let y2 = rng.gen_range(0..=2) as u32;
-
-                            let x3 = rng.gen_range(0..=2) as u32;
-                            let y3 = rng.gen_range(0..=2) as u32;
-
-                            let p1 = match is_in_bounds(x + x1, y + y1, width, height) {
-                                true => input.get_pixel(x + x1, y + y1).0,
-                                false => [0, 0, 0, 0],
-                            };
-
-                            let p2 = match is_in_bounds(x + x2, y + y2, width, height) {
-                                true => input.get_pixel(x + x2, y + y2).0,
-                                false => [0, 0, 0, 0],
-                            };
-
-                            let p3 = match is_in_bounds(x + x3, y + y3, width, height) {
-                                true => input.get_pixel(x + x3, y + y3).0,
-                                false => [0, 0, 0, 0],
-                            };
-
-                            let v_r = RgbSum {
-                                r: p1[0],
-                                g: p2[1],
-                                b: p3[2],
-                            };
-
-                            saved.v_r = Some(v_r);
-                            v_r
-                        }
-                    };
+pub(crate) fn bit_and_not(a: u8, b: u8) -> u8 {
+    a & !b
+}
 
-                    stack.push(v_r);
-                }
-                'e' => {
-                    let v_e = match saved.v_e {
-                        Some(v_e) => v_e,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let rr = boxed[8]
-                                .r
-                                .wrapping_sub(boxed[0].r)
-                                .wrapping_add(boxed[5].r)
-                                .wrapping_sub(boxed[3].r)
-                                .wrapping_add(boxed[7].r)
-                                .wrapping_sub(boxed[1].r)
-                                .wrapping_add(boxed[6].r)
-                                .wrapping_sub(boxed[2].r);
-
-                            let gg = boxed[8]
-                                .g
-                                .wrapping_sub(boxed[0].g)
-                                .wrapping_add(boxed[5].g)
-                                .wrapping_sub(boxed[3].g)
-                                .wrapping_add(boxed[7].g)
-                                .wrapping_sub(boxed[1].g)
-                                .wrapping_add(boxed[6].g)
-                                .wrapping_sub(boxed[2].g);
-
-                            let bb = boxed[8]
-                                .b
-                                .wrapping_sub(boxed[0].b)
-                                .wrapping_add(boxed[5].b)
-                                .wrapping_sub(boxed[3].b)
-                                .wrapping_add(boxed[7].b)
-                                .wrapping_sub(boxed[1].b)
-                                .wrapping_add(boxed[6].b)
-                                .wrapping_sub(boxed[2].b);
-
-                            let v_e = RgbSum {
-                                r: rr,
-                                g: gg,
-                                b: bb,
-                            };
-                            saved.v_e = Some(v_e);
-                            v_e
-                        }
-                    };
+pub(crate) fn weight(a: u8, b: u8) -> u8 {
+    let fuzz = f64::from(b) / 255.0;
+    let r = f64::from(a) * fuzz;
+    r as u8
+}
 
-                    stack.push(v_e);
-                }
-                'b' => {
-                    let v_b = match saved.v_b {
-                        Some(v_b) => v_b,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let rr = wrapping_vec_add_u32([
-                                boxed[0].r, boxed[1].r, boxed[2].r, boxed[3].r, boxed[5].r,
-                                boxed[6].r, boxed[7].r, boxed[8].r,
-                            ]);
-                            let gg = wrapping_vec_add_u32([
-                                boxed[0].g, boxed[1].g, boxed[2].g, boxed[3].g, boxed[5].g,
-                                boxed[6].g, boxed[7].g, boxed[8].g,
-                            ]);
-                            let bb = wrapping_vec_add_u32([
-                                boxed[0].b, boxed[1].b, boxed[2].b, boxed[3].b, boxed[5].b,
-                                boxed[6].b, boxed[7].b, boxed[8].b,
-                            ]);
-
-                            let v_b = RgbSum {
-                                r: (rr / 9) as u8,
-                                g: (gg / 9) as u8,
-                                b: (bb / 9) as u8,
-                            };
-                            saved.v_b = Some(v_b);
-                            v_b
-                        }
-                    };
+pub(crate) fn three_rule(x: u32, max: u32) -> u8 {
+    (((255 * x) / max) & 255) as u8
+}
 
-                    stack.push(v_b);
-                }
-                'H' => {
-                    let v_h = match saved.v_high {
-                        Some(v_h) => v_h,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let r_m = max([
-                                boxed[0].r, boxed[1].r, boxed[2].r, boxed[3].r, boxed[5].r,
-                                boxed[6].r, boxed[7].r, boxed[8].r,
-                            ]);
-                            let g_m = max([
-                                boxed[0].g, boxed[1].g, boxed[2].g, boxed[3].g, boxed[5].g,
-                                boxed[6].g, boxed[7].g, boxed[8].g,
-                            ]);
-                            let b_m = max([
-                                boxed[0].b, boxed[1].b, boxed[2].b, boxed[3].b, boxed[5].b,
-                                boxed[6].b, boxed[7].b, boxed[8].b,
-                            ]);
-
-                            let v_h = RgbSum {
-                                r: r_m,
-                                g: g_m,
-                                b: b_m,
-                            };
-
-                            saved.v_high = Some(v_h);
-                            v_h
-                        }
-                    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
 
-                    stack.push(v_h);
-                }
-                'L' => {
-                    let v_l = match saved.v_low {
-                        Some(v_l) => v_l,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let r_m = min([
-                                boxed[0].r, boxed[1].r, boxed[2].r, boxed[3].r, boxed[5].r,
-                                boxed[6].r, boxed[7].r, boxed[8].r,
-                            ]);
-                            let g_m = min([
-                                boxed[0].g, boxed[1].g, boxed[2].g, boxed[3].g, boxed[5].g,
-                                boxed[6].g, boxed[7].g, boxed[8].g,
-                            ]);
-                            let b_m = min([
-                                boxed[0].b, boxed[1].b, boxed[2].b, boxed[3].b, boxed[5].b,
-                                boxed[6].b, boxed[7].b, boxed[8].b,
-                            ]);
-
-                            let v_l = RgbSum {
-                                r: r_m,
-                                g: g_m,
-                                b: b_m,
-                            };
-
-                            saved.v_low = Some(v_l);
-                            v_l
-                        }
-                    };
+    fn rgb(r: u8, g: u8, b: u8) -> RgbSum {
+        RgbSum { r, g, b }
+    }
 
-                    stack.push(v_l);
-                }
-                'N' => stack.push(RgbSum {
-                    r: rng.gen_range(0..=255),
-                    g: rng.gen_range(0..=255),
-                    b: rng.gen_range(0..=255),
-                }),
-                'h' => {
-                    let v_h = match saved.v_h {
-                        Some(v_h) => v_h,
-                        None => {
-                            let h = width - x - 1;
-                            let pixel = input.get_pixel(h, y).0;
-
-                            let v_h = RgbSum {
-                                r: pixel[0],
-                                g: pixel[1],
-                                b: pixel[2],
-                            };
-
-                            saved.v_h = Some(v_h);
-                            v_h
-                        }
-                    };
+    fn lanes(v: RgbSum) -> [u8; 3] {
+        [v.r, v.g, v.b]
+    }
 
-                    stack.push(v_h);
-                }
-                'v' => {
-                    let v_v = match saved.v_v {
-                        Some(v_v) => v_v,
-                        None => {
-                            let v = height - y - 1;
-                            let pixel = input.get_pixel(x, v).0;
-
-                            let v_v = RgbSum {
-                                r: pixel[0],
-                                g: pixel[1],
-                                b: pixel[2],
-                            };
-
-                            saved.v_v = Some(v_v);
-                            v_v
-                        }
-                    };
+    #[test]
+    fn test_min_max_per_channel() {
+        let args = [rgb(10, 200, 30), rgb(20, 100, 30), rgb(5, 150, 255)];
+        assert_eq!(lanes(call(Func::Min, &args)), [5, 100, 30]);
+        assert_eq!(lanes(call(Func::Max, &args)), [20, 200, 255]);
+    }
 
-                    stack.push(v_v);
-                }
-                'd' => {
-                    let v_d = match saved.v_d {
-                        Some(v_d) => v_d,
-                        None => {
-                            let x = width - x - 1;
-                            let y = height - y - 1;
-                            let pixel = input.get_pixel(x, y).0;
-
-                            let v_d = RgbSum {
-                                r: pixel[0],
-                                g: pixel[1],
-                                b: pixel[2],
-                            };
-
-                            saved.v_d = Some(v_d);
-                            v_d
-                        }
-                    };
+    #[test]
+    fn test_clamp() {
+        let args = [rgb(10, 100, 250), RgbSum::splat(30), RgbSum::splat(200)];
+        assert_eq!(lanes(call(Func::Clamp, &args)), [30, 100, 200]);
+    }
 
-                    stack.push(v_d);
-                }
-                _ => return Err(format!("Unexpected token: {:?}", c)),
-            },
+    #[test]
+    fn test_avg_rounds_down() {
+        let args = [rgb(255, 0, 1), rgb(255, 1, 2)];
+        assert_eq!(lanes(call(Func::Avg, &args)), [255, 0, 1]);
+    }
 
-            _ => return Err(format!("Unexpected token: {:?}", tok)),
-        }
+    #[test]
+    fn test_select_per_channel() {
+        let mask = rgb(255, 0, 1);
+        let picked = RgbSum::select(mask, rgb(1, 2, 3), rgb(4, 5, 6));
+        assert_eq!(lanes(picked), [1, 5, 3]);
     }
 
-    let col = stack.last().ok_or("Stack underflow")?;
-    Ok(Rgba([col.r, col.g, col.b, a]))
-}
+    #[test]
+    fn test_swizzle() {
+        let v = rgb(1, 2, 3);
+        assert_eq!(lanes(v.swizzle([1, 2, 0])), [2, 3, 1]);
+        assert_eq!(lanes(v.swizzle([2, 2, 2])), [3, 3, 3]);
+    }
 
-fn fetch_boxed(input: &DynamicImage, x: i32, y: i32, r: u8, g: u8, b: u8) -> [RgbSum; 9] {
-    let mut k = 0;
+    #[test]
+    fn test_lerp_endpoints() {
+        let (a, b) = (rgb(0, 100, 255), rgb(255, 50, 0));
+        assert_eq!(
+            lanes(call(Func::Lerp, &[a, b, RgbSum::splat(0)])),
+            [0, 100, 255]
+        );
+        assert_eq!(
+            lanes(call(Func::Lerp, &[a, b, RgbSum::splat(255)])),
+            [255, 50, 0]
+        );
+        assert_eq!(
+            lanes(call(Func::Lerp, &[a, b, RgbSum::splat(128)])),
+            [128, 74, 127]
+        );
+    }
 
-    let mut boxed: [RgbSum; 9] = [RgbSum { r: 0, g: 0, b: 0 }; 9];
+    #[test]
+    fn test_offset_edge_modes() {
+        let mut img = image::RgbaImage::new(3, 1);
+        for x in 0..3 {
+            img.put_pixel(x, 0, Rgba([x as u8 * 10, 0, 0, 255]));
+        }
+        let img = DynamicImage::from(img);
+        let program = Compiled::new(&crate::parser::parse("c[1, 0] + c[-2, 0].r").unwrap());
+        let mut machine = Machine::new(&program);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut at = |x: u32, edge: EdgeMode| {
+            let kernels = Kernels::new(&img, edge);
+            let ctx =
+                EvalContext::for_test(&program, &mut machine, &kernels, &mut rng, &img, (x, 0));
+            eval(ctx, &img).unwrap()[0]
+        };
+
+        assert_eq!(at(2, EdgeMode::Zero), 0);
+        assert_eq!(at(2, EdgeMode::Clamp), 20);
+        assert_eq!(at(1, EdgeMode::Clamp), 20);
+        assert_eq!(at(0, EdgeMode::Wrap), 10 + 10);
+        assert_eq!(at(0, EdgeMode::Mirror), 10 + 20);
+    }
 
-    for i in x - 1..=x + 1 {
-        for j in y - 1..=y + 1 {
-            if i == x && j == y {
-                boxed[k] = RgbSum { r, g, b };
-                k += 1;
-                continue;
-            }
+    #[test]
+    fn test_neighborhood_edge_modes() {
+        let img = DynamicImage::from(image::RgbaImage::from_pixel(3, 3, Rgba([90, 90, 90, 255])));
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut at = |expr: &str, (x, y): (u32, u32), edge: EdgeMode| {
+            let program = Compiled::new(&crate::parser::parse(expr).unwrap());
+            let mut machine = Machine::new(&program);
+            let kernels = Kernels::new(&img, edge);
+            let ctx =
+                EvalContext::for_test(&program, &mut machine, &kernels, &mut rng, &img, (x, y));
+            eval(ctx, &img).unwrap()[0]
+        };
+
+        // `b` sums the 8 neighbors and divides by 9.
+        assert_eq!(at("b", (1, 1), EdgeMode::Zero), 80);
+        assert_eq!(at("b", (0, 0), EdgeMode::Zero), 30);
+        assert_eq!(at("b", (2, 2), EdgeMode::Zero), 30);
+        for edge in [
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+            EdgeMode::Transparent,
+        ] {
+            assert_eq!(at("b", (2, 0), edge), 80, "{:?}", edge);
+            assert_eq!(at("e", (0, 2), edge), 0, "{:?}", edge);
+            assert_eq!(at("L", (2, 2), edge), 90, "{:?}", edge);
+            assert_eq!(at("r", (2, 2), edge), 90, "{:?}", edge);
+        }
+        assert_eq!(at("e", (0, 0), EdgeMode::Zero), (3 * 90u32 % 256) as u8);
+        assert_eq!(at("L", (2, 2), EdgeMode::Zero), 0);
+        assert_eq!(at("c[-1, 0]", (0, 0), EdgeMode::Transparent), 90);
+        assert_eq!(at("b[-1, 0]", (0, 1), EdgeMode::Transparent), 80);
+    }
 
-            if i < 0 || j < 0 {
-                boxed[k] = RgbSum { r: 0, g: 0, b: 0 };
-                k += 1;
-                continue;
+    #[test]
+    fn test_radius_one_is_neighborhood() {
+        let img = crate::fixtures::image(4, 3);
+        let program = Compiled::new(
+            &crate::parser::parse("[b == blur(1), e == edge(1), H == high(1), L == L(1)]").unwrap(),
+        );
+        let mut machine = Machine::new(&program);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        for edge in [
+            EdgeMode::Zero,
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+            EdgeMode::Transparent,
+        ] {
+            let kernels = Kernels::new(&img, edge);
+            for (x, y, _) in img.pixels() {
+                let ctx =
+                    EvalContext::for_test(&program, &mut machine, &kernels, &mut rng, &img, (x, y));
+                let at = (edge, x, y);
+                assert_eq!(eval(ctx, &img).unwrap(), Rgba([255; 4]), "{:?}", at);
             }
-
-            let pixel = input.get_pixel(i as u32, j as u32).0;
-            boxed[k] = RgbSum {
-                r: pixel[0],
-                g: pixel[1],
-                b: pixel[2],
-            };
-            k += 1;
         }
     }
-    boxed
-}
 
-fn max(vals: [u8; 8]) -> u8 {
-    vals.iter().cloned().max().unwrap_or_default()
-}
+    #[test]
+    fn test_seeded_noise_is_reproducible() {
+        let img = DynamicImage::from(image::RgbaImage::from_pixel(2, 2, Rgba([9; 4])));
+        let program = Compiled::new(&crate::parser::parse("N ^ r").unwrap());
+        let mut machine = Machine::new(&program);
+
+        let mut run = |seed: u64| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            (0..4)
+                .map(|i| {
+                    let kernels = Kernels::new(&img, EdgeMode::Zero);
+                    let ctx = EvalContext {
+                        seed,
+                        ..EvalContext::for_test(
+                            &program,
+                            &mut machine,
+                            &kernels,
+                            &mut rng,
+                            &img,
+                            (i % 2, i / 2),
+                        )
+                    };
+                    eval(ctx, &img).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
 
-fn min(vals: [u8; 8]) -> u8 {
-    vals.iter().cloned().min().unwrap_or_default()
-}
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn test_noise_ignores_rng_state() {
+        let img = DynamicImage::from(image::RgbaImage::from_pixel(4, 4, Rgba([9; 4])));
+        let program =
+            Compiled::new(&crate::parser::parse("hash() ^ perlin(3) + vnoise(2)").unwrap());
+        let mut machine = Machine::new(&program);
+
+        let mut at = |rng_seed: u64, frame: u32| {
+            let mut rng = ChaCha8Rng::seed_from_u64(rng_seed);
+            let kernels = Kernels::new(&img, EdgeMode::Zero);
+            let ctx = EvalContext {
+                seed: 5,
+                frame,
+                ..EvalContext::for_test(&program, &mut machine, &kernels, &mut rng, &img, (2, 1))
+            };
+            eval(ctx, &img).unwrap()
+        };
 
-fn wrapping_vec_add_u32(a: [u8; 8]) -> u32 {
-    let mut sum: u32 = 0;
-    for i in a {
-        sum = sum.wrapping_add(i as u32);
+        assert_eq!(at(1, 0), at(2, 0));
+        assert_ne!(at(1, 0), at(1, 1));
     }
-    sum
-}
\ No newline at end of file
+
+    #[test]
+    fn test_machine_branches_and_bindings() {
+        let img = DynamicImage::from(image::RgbaImage::from_pixel(1, 1, Rgba([10, 20, 30, 255])));
+        let program = crate::parser::parse(
+            "let k = c ^ 7; let m = [255, 0, k]; if m then k else (if 0 then N else c + 1)",
+        )
+        .unwrap();
+        let program = Compiled::new(&program);
+        let mut machine = Machine::new(&program);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let capacity = machine.stack.capacity();
+
+        for _ in 0..3 {
+            let kernels = Kernels::new(&img, EdgeMode::Zero);
+            let ctx =
+                EvalContext::for_test(&program, &mut machine, &kernels, &mut rng, &img, (0, 0));
+            assert_eq!(eval(ctx, &img).unwrap(), Rgba([13, 21, 25, 255]));
+        }
+        assert_eq!(machine.stack.capacity(), capacity);
+        assert!(machine.stack.is_empty());
+    }
+}
@@ -0,0 +1,22 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// An image for tests, where every component of a pixel depends on both of its
+/// coordinates so reading the wrong one shows. Pixels on every seventh diagonal
+/// are transparent and the alpha of the others varies with `x`.
+pub fn image(width: u32, height: u32) -> DynamicImage {
+    let mut img = RgbaImage::new(width, height);
+    for (x, y, p) in img.enumerate_pixels_mut() {
+        let a = if (x + y) % 7 == 6 {
+            0
+        } else {
+            255 - (x as u8 & 15)
+        };
+        *p = Rgba([
+            (x * 37 + y * 11) as u8,
+            (x * y * 5 + 3) as u8,
+            ((x ^ (y * 7)) * 9) as u8,
+            a,
+        ]);
+    }
+    DynamicImage::from(img)
+}
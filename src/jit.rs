@@ -0,0 +1,617 @@
+use std::ops::Range;
+
+use anyhow::anyhow;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, SigRef, Type, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use crate::ast::{BinOp, Expr, ExprKind, Func, Program, UnOp, Var};
+
+/// `(image, row, width, height, y, x_start, x_end, frame)`, evaluating the pixels
+/// `x_start..x_end` of row `y` of a packed RGBA image into `row`.
+type RowFn = unsafe extern "C" fn(*const u8, *mut u8, u32, u32, u32, u32, u32, u32);
+
+/// Native code evaluating a program over a row of pixels, with the same result
+/// as [`crate::eval::eval`] on each of them.
+pub struct Kernel {
+    row: RowFn,
+    module: Option<JITModule>,
+}
+
+// SAFETY: the module is immutable from `finalize_definitions` until the kernel is
+// dropped. The code it holds keeps no state of its own, it only reads the input and
+// writes the row it is given, so it is reentrant and threads can run it at once.
+unsafe impl Sync for Kernel {}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `row` points into the module and goes away with `self`.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Whether the program only reads the current pixel, its mirrored ones and its
+/// position, which is what a kernel can evaluate. The others depend on the
+/// generator, the pixel above or a neighborhood and are left to the interpreter.
+pub fn supports(program: &Program) -> bool {
+    program.remap.is_none()
+        && program
+            .bindings
+            .iter()
+            .map(|binding| &binding.value)
+            .chain([&program.body])
+            .chain(&program.alpha)
+            .all(supported)
+}
+
+fn supported(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Num(_) | ExprKind::Local { .. } => true,
+        ExprKind::Var(var) => matches!(
+            var,
+            Var::Color
+                | Var::Red
+                | Var::Green
+                | Var::Blue
+                | Var::Lum
+                | Var::X
+                | Var::Y
+                | Var::Frame
+                | Var::HFlip
+                | Var::VFlip
+                | Var::DFlip
+        ),
+        ExprKind::Offset { .. } | ExprKind::Convolve { .. } => false,
+        ExprKind::Unary { operand, .. }
+        | ExprKind::Swizzle { operand, .. }
+        | ExprKind::Group(operand) => supported(operand),
+        ExprKind::Binary { lhs, rhs, .. } => supported(lhs) && supported(rhs),
+        ExprKind::Call { func, args } => !func.reads_pixel() && args.iter().all(supported),
+        ExprKind::Cond {
+            cond,
+            if_true,
+            if_false,
+        } => supported(cond) && supported(if_true) && supported(if_false),
+        ExprKind::Channels(items) => items.iter().all(supported),
+    }
+}
+
+impl Kernel {
+    /// Compiles a program accepted by [`supports`].
+    pub fn new(program: &Program) -> anyhow::Result<Self> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false")?;
+        flags.set("is_pic", "false")?;
+        flags.set("opt_level", "speed")?;
+        let isa = cranelift_native::builder()
+            .map_err(|msg| anyhow!("host machine is not supported: {}", msg))?
+            .finish(settings::Flags::new(flags))?;
+
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        let ptr = module.target_config().pointer_type();
+
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(ptr));
+        ctx.func.signature.params.push(AbiParam::new(ptr));
+        for _ in 0..6 {
+            ctx.func.signature.params.push(AbiParam::new(types::I32));
+        }
+        let id = module.declare_function("row", Linkage::Local, &ctx.func.signature)?;
+
+        let mut pow = module.make_signature();
+        pow.params.push(AbiParam::new(types::I32));
+        pow.params.push(AbiParam::new(types::I32));
+        pow.returns.push(AbiParam::new(types::I32));
+
+        let mut fn_ctx = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+        let pow = b.import_signature(pow);
+        build_row(&mut b, ptr, pow, program);
+        b.finalize();
+
+        module.define_function(id, &mut ctx)?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions()?;
+
+        // SAFETY: the function was declared with the parameters of `RowFn`.
+        let row =
+            unsafe { std::mem::transmute::<*const u8, RowFn>(module.get_finalized_function(id)) };
+        Ok(Kernel {
+            row,
+            module: Some(module),
+        })
+    }
+
+    /// Evaluates the pixels `xs` of row `y` of `input`, a packed RGBA image, into
+    /// `row`. The other pixels of `row` are left as they are.
+    pub fn run(
+        &self,
+        input: &[u8],
+        (width, height): (u32, u32),
+        frame: u32,
+        y: u32,
+        xs: Range<u32>,
+        row: &mut [u8],
+    ) {
+        assert_eq!(input.len(), width as usize * height as usize * 4);
+        assert_eq!(row.len(), width as usize * 4);
+        assert!(y < height && xs.start <= xs.end && xs.end <= width);
+
+        // SAFETY: the kernel reads pixels inside of the image and writes the pixels
+        // `xs` of the row, both checked above.
+        unsafe {
+            (self.row)(
+                input.as_ptr(),
+                row.as_mut_ptr(),
+                width,
+                height,
+                y,
+                xs.start,
+                xs.end,
+                frame,
+            )
+        }
+    }
+}
+
+/// A value of the program, one `i8` per color component.
+type Lanes = [Value; 3];
+
+/// Emits the loop over the pixels of a row. Transparent pixels become
+/// transparent black like in the interpreter, the others run the program.
+fn build_row(b: &mut FunctionBuilder, ptr: Type, pow: SigRef, program: &Program) {
+    let entry = b.create_block();
+    let header = b.create_block();
+    let body = b.create_block();
+    let opaque = b.create_block();
+    let transparent = b.create_block();
+    let next = b.create_block();
+    let exit = b.create_block();
+
+    b.append_block_params_for_function_params(entry);
+    b.switch_to_block(entry);
+    let [input, row, width, height, y, x_start, x_end, frame] = b.block_params(entry) else {
+        unreachable!("the row function takes 8 parameters")
+    };
+    let [input, row, width, height, y, x_start, x_end, frame] =
+        [*input, *row, *width, *height, *y, *x_start, *x_end, *frame];
+    b.ins().jump(header, &[x_start]);
+
+    b.append_block_param(header, types::I32);
+    b.switch_to_block(header);
+    let x = b.block_params(header)[0];
+    let done = b.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, x, x_end);
+    b.ins().brif(done, exit, &[], body, &[]);
+
+    b.switch_to_block(body);
+    let mut gen = Codegen {
+        b,
+        ptr,
+        pow,
+        input,
+        width,
+        height,
+        x,
+        y,
+        frame,
+        rgb: [x; 3],
+        locals: Vec::with_capacity(program.bindings.len()),
+    };
+    let [r, g, bl, a] = gen.load(x, y);
+    gen.rgb = [r, g, bl];
+    let x_bytes = gen.b.ins().uextend(ptr, x);
+    let x_bytes = gen.b.ins().imul_imm(x_bytes, 4);
+    let out = gen.b.ins().iadd(row, x_bytes);
+    gen.b.ins().brif(a, opaque, &[], transparent, &[]);
+
+    gen.b.switch_to_block(transparent);
+    let zero = gen.b.ins().iconst(types::I32, 0);
+    gen.b.ins().store(MemFlags::trusted(), zero, out, 0);
+    gen.b.ins().jump(next, &[]);
+
+    gen.b.switch_to_block(opaque);
+    for binding in &program.bindings {
+        let value = gen.expr(&binding.value);
+        gen.locals.push(value);
+    }
+    let color = gen.expr(&program.body);
+    let alpha = match &program.alpha {
+        Some(alpha) => gen.expr(alpha)[0],
+        None => a,
+    };
+    for (offset, value) in color.into_iter().chain([alpha]).enumerate() {
+        gen.b
+            .ins()
+            .store(MemFlags::trusted(), value, out, offset as i32);
+    }
+    gen.b.ins().jump(next, &[]);
+
+    let b = gen.b;
+    b.switch_to_block(next);
+    let x = b.ins().iadd_imm(x, 1);
+    b.ins().jump(header, &[x]);
+
+    b.switch_to_block(exit);
+    b.ins().return_(&[]);
+    b.seal_all_blocks();
+}
+
+struct Codegen<'a, 'b> {
+    b: &'a mut FunctionBuilder<'b>,
+    ptr: Type,
+    pow: SigRef,
+    input: Value,
+    width: Value,
+    height: Value,
+    x: Value,
+    y: Value,
+    frame: Value,
+    rgb: Lanes,
+    locals: Vec<Lanes>,
+}
+
+impl Codegen<'_, '_> {
+    fn splat(&mut self, n: u8) -> Lanes {
+        [self.b.ins().iconst(types::I8, i64::from(n)); 3]
+    }
+
+    fn consts(&mut self, values: [u8; 3]) -> Lanes {
+        values.map(|n| self.b.ins().iconst(types::I8, i64::from(n)))
+    }
+
+    /// The four components of the input pixel at `(x, y)`.
+    fn load(&mut self, x: Value, y: Value) -> [Value; 4] {
+        let ptr = self.ptr;
+        let x = self.b.ins().uextend(ptr, x);
+        let y = self.b.ins().uextend(ptr, y);
+        let width = self.b.ins().uextend(ptr, self.width);
+        let index = self.b.ins().imul(y, width);
+        let index = self.b.ins().iadd(index, x);
+        let offset = self.b.ins().imul_imm(index, 4);
+        let addr = self.b.ins().iadd(self.input, offset);
+        [0, 1, 2, 3].map(|i| {
+            self.b
+                .ins()
+                .load(types::I8, MemFlags::trusted().with_readonly(), addr, i)
+        })
+    }
+
+    /// `len - 1 - pos`, the coordinate mirrored on an axis.
+    fn flip(&mut self, pos: Value, len: Value) -> Value {
+        let v = self.b.ins().isub(len, pos);
+        self.b.ins().iadd_imm(v, -1)
+    }
+
+    /// The same as [`crate::eval::three_rule`].
+    fn three_rule(&mut self, pos: Value, len: Value) -> Lanes {
+        let v = self.b.ins().imul_imm(pos, 255);
+        let v = self.b.ins().udiv(v, len);
+        [self.b.ins().ireduce(types::I8, v); 3]
+    }
+
+    fn var(&mut self, var: Var) -> Lanes {
+        let (x, y) = (self.x, self.y);
+        let (width, height) = (self.width, self.height);
+        let rgb = |[r, g, b, _]: [Value; 4]| [r, g, b];
+        match var {
+            Var::Color => self.rgb,
+            Var::Red => self.consts([255, 0, 0]),
+            Var::Green => self.consts([0, 255, 0]),
+            Var::Blue => self.consts([0, 0, 255]),
+            Var::Lum => {
+                let weights = [0.299, 0.587, 0.0722];
+                let mut sum = None;
+                for (v, weight) in self.rgb.into_iter().zip(weights) {
+                    let v = self.b.ins().uextend(types::I32, v);
+                    let v = self.b.ins().fcvt_from_uint(types::F64, v);
+                    let weight = self.b.ins().f64const(weight);
+                    let v = self.b.ins().fmul(v, weight);
+                    sum = Some(match sum {
+                        Some(sum) => self.b.ins().fadd(sum, v),
+                        None => v,
+                    });
+                }
+                let v = self.b.ins().fcvt_to_uint_sat(types::I32, sum.unwrap());
+                [self.b.ins().ireduce(types::I8, v); 3]
+            }
+            Var::X => self.three_rule(x, width),
+            Var::Y => self.three_rule(y, height),
+            Var::Frame => [self.b.ins().ireduce(types::I8, self.frame); 3],
+            Var::HFlip => {
+                let x = self.flip(x, width);
+                rgb(self.load(x, y))
+            }
+            Var::VFlip => {
+                let y = self.flip(y, height);
+                rgb(self.load(x, y))
+            }
+            Var::DFlip => {
+                let x = self.flip(x, width);
+                let y = self.flip(y, height);
+                rgb(self.load(x, y))
+            }
+            _ => unreachable!("{:?} is not supported by kernels", var),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Lanes {
+        match &expr.kind {
+            ExprKind::Num(n) => self.splat(*n),
+            ExprKind::Var(var) => self.var(*var),
+            ExprKind::Local { slot, .. } => self.locals[*slot],
+            ExprKind::Unary { op, operand } => {
+                let v = self.expr(operand);
+                v.map(|v| self.unary(*op, v))
+            }
+            ExprKind::Swizzle { operand, lanes } => {
+                let v = self.expr(operand);
+                lanes.map(|lane| v[lane as usize])
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let (a, b) = (self.expr(lhs), self.expr(rhs));
+                [0, 1, 2].map(|i| self.binary(*op, a[i], b[i]))
+            }
+            ExprKind::Call { func, args } => {
+                let args: Vec<Lanes> = args.iter().map(|arg| self.expr(arg)).collect();
+                [0, 1, 2].map(|i| {
+                    let lane: Vec<Value> = args.iter().map(|arg| arg[i]).collect();
+                    self.call(*func, &lane)
+                })
+            }
+            // Both branches are computed and picked per component, without `N`,
+            // `r` or `s` skipping one makes no difference.
+            ExprKind::Cond {
+                cond,
+                if_true,
+                if_false,
+            } => {
+                let mask = self.expr(cond);
+                let (a, b) = (self.expr(if_true), self.expr(if_false));
+                [0, 1, 2].map(|i| self.b.ins().select(mask[i], a[i], b[i]))
+            }
+            ExprKind::Channels(items) => {
+                let items: Vec<Lanes> = items.iter().map(|item| self.expr(item)).collect();
+                [items[0][0], items[1][1], items[2][2]]
+            }
+            ExprKind::Group(inner) => self.expr(inner),
+            ExprKind::Offset { .. } => unreachable!("offsets are not supported by kernels"),
+            ExprKind::Convolve { .. } => unreachable!("convolutions are not supported by kernels"),
+        }
+    }
+
+    fn unary(&mut self, op: UnOp, v: Value) -> Value {
+        match op {
+            UnOp::Neg => self.b.ins().ineg(v),
+            UnOp::Not => self.b.ins().bnot(v),
+            // Widened first so `abs -128` wraps back to 128 as a `u8`.
+            UnOp::Abs => {
+                let v = self.b.ins().sextend(types::I32, v);
+                let v = self.b.ins().iabs(v);
+                self.b.ins().ireduce(types::I8, v)
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, a: Value, b: Value) -> Value {
+        let compare = |this: &mut Self, cc: IntCC| {
+            let v = this.b.ins().icmp(cc, a, b);
+            this.b.ins().ineg(v)
+        };
+        match op {
+            BinOp::Add => self.b.ins().iadd(a, b),
+            BinOp::Sub => self.b.ins().isub(a, b),
+            BinOp::Mul => self.b.ins().imul(a, b),
+            // Dividing by zero gives back `a`, like the interpreter.
+            BinOp::Div | BinOp::Mod => {
+                let zero = self.b.ins().icmp_imm(IntCC::Equal, b, 0);
+                let one = self.b.ins().iconst(types::I8, 1);
+                let divisor = self.b.ins().select(zero, one, b);
+                let v = match op {
+                    BinOp::Div => self.b.ins().udiv(a, divisor),
+                    _ => self.b.ins().urem(a, divisor),
+                };
+                self.b.ins().select(zero, a, v)
+            }
+            BinOp::Pow => {
+                let a = self.b.ins().uextend(types::I32, a);
+                let b = self.b.ins().uextend(types::I32, b);
+                let callee = self.b.ins().iconst(self.ptr, pow as *const () as i64);
+                let call = self.b.ins().call_indirect(self.pow, callee, &[a, b]);
+                let v = self.b.inst_results(call)[0];
+                self.b.ins().ireduce(types::I8, v)
+            }
+            BinOp::BitAnd => self.b.ins().band(a, b),
+            BinOp::BitOr => self.b.ins().bor(a, b),
+            BinOp::BitAndNot => self.b.ins().band_not(a, b),
+            BinOp::BitXor => self.b.ins().bxor(a, b),
+            BinOp::BitLShift | BinOp::BitRShift => {
+                let shift = self.b.ins().band_imm(b, 7);
+                match op {
+                    BinOp::BitLShift => self.b.ins().ishl(a, shift),
+                    _ => self.b.ins().ushr(a, shift),
+                }
+            }
+            BinOp::Weight => {
+                let [a, b] = [a, b].map(|v| {
+                    let v = self.b.ins().uextend(types::I32, v);
+                    self.b.ins().fcvt_from_uint(types::F64, v)
+                });
+                let max = self.b.ins().f64const(255.0);
+                let fuzz = self.b.ins().fdiv(b, max);
+                let v = self.b.ins().fmul(a, fuzz);
+                let v = self.b.ins().fcvt_to_uint_sat(types::I32, v);
+                self.b.ins().ireduce(types::I8, v)
+            }
+            BinOp::Greater | BinOp::Gt => compare(self, IntCC::UnsignedGreaterThan),
+            BinOp::Lt => compare(self, IntCC::UnsignedLessThan),
+            BinOp::Le => compare(self, IntCC::UnsignedLessThanOrEqual),
+            BinOp::Eq => compare(self, IntCC::Equal),
+            BinOp::Ne => compare(self, IntCC::NotEqual),
+            BinOp::Ge => compare(self, IntCC::UnsignedGreaterThanOrEqual),
+        }
+    }
+
+    fn call(&mut self, func: Func, args: &[Value]) -> Value {
+        match func {
+            Func::Min => args[1..]
+                .iter()
+                .fold(args[0], |acc, v| self.b.ins().umin(acc, *v)),
+            Func::Max => args[1..]
+                .iter()
+                .fold(args[0], |acc, v| self.b.ins().umax(acc, *v)),
+            Func::Clamp => {
+                let v = self.b.ins().umax(args[0], args[1]);
+                self.b.ins().umin(v, args[2])
+            }
+            Func::Avg => {
+                let mut sum = self.b.ins().iconst(types::I32, 0);
+                for arg in args {
+                    let v = self.b.ins().uextend(types::I32, *arg);
+                    sum = self.b.ins().iadd(sum, v);
+                }
+                let n = self.b.ins().iconst(types::I32, args.len() as i64);
+                let v = self.b.ins().udiv(sum, n);
+                self.b.ins().ireduce(types::I8, v)
+            }
+            Func::Lerp => {
+                let [a, b, t] =
+                    [args[0], args[1], args[2]].map(|v| self.b.ins().uextend(types::I32, v));
+                let max = self.b.ins().iconst(types::I32, 255);
+                let inv = self.b.ins().isub(max, t);
+                let a = self.b.ins().imul(a, inv);
+                let b = self.b.ins().imul(b, t);
+                let v = self.b.ins().iadd(a, b);
+                let v = self.b.ins().udiv(v, max);
+                self.b.ins().ireduce(types::I8, v)
+            }
+            Func::Hash | Func::ValueNoise | Func::Perlin => {
+                unreachable!("noise functions are not supported by kernels")
+            }
+            Func::Blur
+            | Func::Edge
+            | Func::High
+            | Func::Low
+            | Func::Gauss
+            | Func::Sobel
+            | Func::Angle
+            | Func::Laplace
+            | Func::Unsharp => {
+                unreachable!("neighborhoods are not supported by kernels")
+            }
+        }
+    }
+}
+
+extern "C" fn pow(a: u32, b: u32) -> u32 {
+    (a as u8).wrapping_pow(b).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::compile::Compiled;
+    use crate::edge::EdgeMode;
+    use crate::eval::{eval, EvalContext, Machine};
+    use crate::fixtures::image;
+    use crate::kernels::Kernels;
+    use crate::parser::parse;
+
+    fn interpreted(program: &Program, img: &DynamicImage, frame: u32) -> Vec<u8> {
+        let program = Compiled::new(program);
+        let mut machine = Machine::new(&program);
+        let kernels = Kernels::new(img, EdgeMode::Zero);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut out = Vec::new();
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                let ctx = EvalContext {
+                    frame,
+                    ..EvalContext::for_test(&program, &mut machine, &kernels, &mut rng, img, (x, y))
+                };
+                out.extend(eval(ctx, img).unwrap().0);
+            }
+        }
+        out
+    }
+
+    fn compiled(kernel: &Kernel, img: &DynamicImage, frame: u32) -> Vec<u8> {
+        let input = img.to_rgba8().into_raw();
+        let mut out = vec![0; input.len()];
+        let width = img.width();
+        for (y, row) in out.chunks_exact_mut(width as usize * 4).enumerate() {
+            kernel.run(&input, img.dimensions(), frame, y as u32, 0..width, row);
+        }
+        out
+    }
+
+    #[test]
+    fn test_supported_programs() {
+        for expr in [
+            "c + x * y",
+            "let k = Y; [h, v, d, k]",
+            "if c > 9 then R else G",
+        ] {
+            assert!(supports(&parse(expr).unwrap()), "{}", expr);
+        }
+        for expr in [
+            "c + N",
+            "s ^ c",
+            "b - e",
+            "c[1, 0]",
+            "perlin(8)",
+            "blur(4)",
+            "x, y",
+        ] {
+            assert!(!supports(&parse(expr).unwrap()), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_same_as_interpreter() {
+        let img = image(41, 17);
+        let expressions = [
+            "c",
+            "(c + x * 3) ^ (y << 2) - frame",
+            "let k = c / (y % 5); let m = k % (x >> 5); [m, k, c # 3, x @ Y]",
+            "if c > 128 then h : v else -d",
+            "[abs (c - h), ~c, (c <= x) | (c == y)]",
+            "min(c, h, v) + max(R, G, B) - clamp(c, 40, 200)",
+            "avg(c, v, 255) + lerp(c, d, y).gbr",
+            "(c >> (x % 9)) & (c < 200) | (c != v) ^ (c >= d)",
+            "c ? 100 @ 200 # 2",
+        ];
+        for expr in expressions {
+            let program = parse(expr).unwrap();
+            let kernel = Kernel::new(&program).unwrap();
+            for frame in [0, 3] {
+                assert_eq!(
+                    compiled(&kernel, &img, frame),
+                    interpreted(&program, &img, frame),
+                    "{}",
+                    expr
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_only_writes_its_pixels() {
+        let img = image(8, 2);
+        let kernel = Kernel::new(&parse("[1, 2, 3, 4]").unwrap()).unwrap();
+        let input = img.to_rgba8().into_raw();
+        let mut row = vec![9; 32];
+        kernel.run(&input, (8, 2), 0, 1, 2..4, &mut row);
+        assert_eq!(row[..8], [9; 8]);
+        assert_eq!(row[8..16], [1, 2, 3, 4, 1, 2, 3, 4]);
+        assert_eq!(row[16..], [9; 16]);
+    }
+}
@@ -0,0 +1,1251 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use image::{DynamicImage, GenericImageView};
+use rayon::prelude::*;
+
+use crate::ast::{Convolution, Func, Overflow, Var};
+use crate::compile::{Compiled, Op};
+use crate::edge::EdgeMode;
+
+/// Number of values a component can take, and so of planes for a kernel with an
+/// argument like a radius.
+const VALUES: usize = 256;
+
+/// Bytes of planes kept for the kernels taking an argument. A radius changing from
+/// pixel to pixel, like `blur(x)`, can ask for all of their planes, more memory than
+/// there is for a large image, so past this the kernels are computed in tiles.
+const CACHE_BYTES: usize = 1 << 30;
+
+/// Bytes of tiles kept, the least recently used ones are dropped past this.
+const TILE_BYTES: usize = 1 << 28;
+
+/// Smallest side of a tile, bigger for kernels reaching further.
+const TILE: usize = 64;
+
+/// Functions reading a neighborhood of the pixel: `blur(r)`, `edge(r)`, `high(r)`
+/// and `low(r)`, the neighborhood variables `b`, `e`, `H` and `L` over a box of
+/// `2 * r + 1` pixels, the filters `gauss`, `sobel`, `angle`, `laplace` and
+/// `unsharp`, and the `kernel` declarations of a program. Each one is computed for
+/// the whole image the first time a pixel asks for it, or up front by
+/// [`Kernels::prepare`]. The box kernels take time that does not grow with the
+/// radius: sums come from a summed-area table and extremes from van Herk/Gil-Werman
+/// running windows.
+///
+/// The variables read from the image are planes too, so evaluating them at a pixel
+/// is a lookup: `b`, `e`, `H` and `L` are the kernels with a radius of 1, and `c`,
+/// the flips and `Y` come from the pixels and luma planes.
+///
+/// Once the planes of the kernels with an argument take [`CACHE_BYTES`], the others
+/// are computed a tile at a time, the same way as the planes, for the pixels that
+/// read them. A tile is at least twice as wide as the kernel reaches, so it reads
+/// no more pixels around it than it holds and a pixel costs the same at any radius.
+pub struct Kernels<'a> {
+    input: &'a DynamicImage,
+    edge: EdgeMode,
+    /// The color of every pixel, row by row.
+    pixels: OnceLock<Vec<[u8; 3]>>,
+    /// `Y` of every pixel.
+    luma: OnceLock<Vec<u8>>,
+    /// Indexed by kernel then argument.
+    planes: Vec<OnceLock<Vec<[u8; 3]>>>,
+    /// Bytes taken by the planes with an argument, at most `budget`.
+    cached: AtomicUsize,
+    budget: usize,
+    /// Parts of the planes that are not kept.
+    tiles: Mutex<Tiles>,
+    tile_budget: usize,
+    convolutions: &'a [Convolution],
+    /// Indexed like `convolutions`.
+    convolved: Vec<OnceLock<Vec<[u8; 3]>>>,
+}
+
+impl fmt::Debug for Kernels<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Kernels")
+            .field("edge", &self.edge)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Kernels<'a> {
+    pub fn new(input: &'a DynamicImage, edge: EdgeMode) -> Self {
+        Kernels {
+            input,
+            edge,
+            pixels: OnceLock::new(),
+            luma: OnceLock::new(),
+            planes: (0..8 * VALUES).map(|_| OnceLock::new()).collect(),
+            cached: AtomicUsize::new(0),
+            budget: CACHE_BYTES,
+            tiles: Mutex::default(),
+            tile_budget: TILE_BYTES,
+            convolutions: &[],
+            convolved: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn edge(&self) -> EdgeMode {
+        self.edge
+    }
+
+    /// Makes the `kernel` declarations of a program available to
+    /// [`Kernels::convolved`].
+    pub fn with_convolutions(mut self, convolutions: &'a [Convolution]) -> Self {
+        self.convolutions = convolutions;
+        self.convolved = convolutions.iter().map(|_| OnceLock::new()).collect();
+        self
+    }
+
+    /// Computes every plane read by the variables, `kernel` declarations and
+    /// kernels with constant arguments like `blur(4)` of `program`, in parallel,
+    /// before any pixel is evaluated.
+    pub fn prepare(&self, program: &Compiled) {
+        let mut vars = Vec::new();
+        let mut slots = Vec::new();
+        let mut calls = Vec::new();
+        for (i, op) in program.code.iter().enumerate() {
+            match *op {
+                Op::Var(var) | Op::Offset(var) if var.is_spatial() && !vars.contains(&var) => {
+                    vars.push(var)
+                }
+                Op::Convolve(slot) if !slots.contains(&slot) => slots.push(slot),
+                // Arguments that are each a single number are the ops right before.
+                Op::Call(func, argc) if func.is_kernel() => {
+                    let args = &program.code[i - argc..i];
+                    let arg = match args.first() {
+                        Some(Op::Num(n)) => *n,
+                        _ => 0,
+                    };
+                    let func = if func == Func::Unsharp {
+                        Func::Gauss
+                    } else {
+                        func
+                    };
+                    let constant = args.iter().all(|op| matches!(op, Op::Num(_)));
+                    if constant && !calls.contains(&(func, arg)) {
+                        calls.push((func, arg));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // The planes are computed side by side, each one on a single thread.
+        rayon::join(
+            || {
+                vars.par_iter().for_each(|&var| {
+                    self.var(var, (0, 0));
+                })
+            },
+            || {
+                rayon::join(
+                    || {
+                        calls.par_iter().for_each(|&(func, arg)| {
+                            self.plane(func, arg);
+                        })
+                    },
+                    || {
+                        slots.par_iter().for_each(|&slot| {
+                            self.convolution(slot);
+                        })
+                    },
+                )
+            },
+        );
+    }
+
+    /// The value of a variable read from the image at `(x, y)`.
+    pub fn var(&self, var: Var, (x, y): (u32, u32)) -> [u8; 3] {
+        let (width, height) = self.input.dimensions();
+        let i = |x: u32, y: u32| y as usize * width as usize + x as usize;
+        match var {
+            Var::Color => self.pixels()[i(x, y)],
+            Var::HFlip => self.pixels()[i(width - x - 1, y)],
+            Var::VFlip => self.pixels()[i(x, height - y - 1)],
+            Var::DFlip => self.pixels()[i(width - x - 1, height - y - 1)],
+            Var::Lum => [self.luma()[i(x, y)]; 3],
+            _ => match Func::with_radius(var) {
+                Some(func) => [0, 1, 2].map(|lane| self.value(func, 1, (x, y), lane)),
+                None => unreachable!("{:?} is not read from the image", var),
+            },
+        }
+    }
+
+    fn pixels(&self) -> &[[u8; 3]] {
+        self.pixels.get_or_init(|| {
+            self.input
+                .pixels()
+                .map(|(_, _, p)| [p.0[0], p.0[1], p.0[2]])
+                .collect()
+        })
+    }
+
+    fn luma(&self) -> &[u8] {
+        self.luma.get_or_init(|| {
+            self.pixels()
+                .iter()
+                .map(|&[r, g, b]| {
+                    let y = f64::from(r) * 0.299 + f64::from(g) * 0.587 + f64::from(b) * 0.0722;
+                    y as u8
+                })
+                .collect()
+        })
+    }
+
+    fn convolution(&self, slot: usize) -> &[[u8; 3]] {
+        self.convolved[slot].get_or_init(|| {
+            convolve(
+                self.input,
+                self.edge,
+                &self.convolutions[slot],
+                self.pixels(),
+            )
+        })
+    }
+
+    /// The image convolved with declaration `slot` at `(x, y)`.
+    pub fn convolved(&self, slot: usize, (x, y): (u32, u32)) -> [u8; 3] {
+        let i = y as usize * self.input.width() as usize + x as usize;
+        self.convolution(slot)[i]
+    }
+
+    /// The value of `func` at `(x, y)`, each component with its own arguments.
+    pub fn at(&self, func: Func, args: &[[u8; 3]], (x, y): (u32, u32)) -> [u8; 3] {
+        let i = y as usize * self.input.width() as usize + x as usize;
+        [0, 1, 2].map(|lane| {
+            let arg = |n: usize| args.get(n).map(|arg| arg[lane]);
+            match func {
+                Func::Unsharp => {
+                    let v = self.pixels()[i][lane];
+                    let blurred = self.value(Func::Gauss, arg(0).unwrap_or(0), (x, y), lane);
+                    unsharp(v, blurred, arg(1).unwrap_or(100))
+                }
+                _ => self.value(func, arg(0).unwrap_or(0), (x, y), lane),
+            }
+        })
+    }
+
+    /// One component of `func` at `(x, y)`, from its plane when it is kept.
+    fn value(&self, func: Func, arg: u8, (x, y): (u32, u32), lane: usize) -> u8 {
+        let i = y as usize * self.input.width() as usize + x as usize;
+        if let Some(plane) = self.plane(func, arg) {
+            return plane[i][lane];
+        }
+
+        let tile = self.tile(func, arg, (x as usize, y as usize));
+        let area = tile.area;
+        let values = tile
+            .values
+            .get_or_init(|| compute(self.input, self.edge, func, arg, self.pixels(), area));
+        values[(y as usize - area.y) * area.width + x as usize - area.x][lane]
+    }
+
+    /// The tile of the plane of `func` for `arg` holding `(x, y)`.
+    fn tile(&self, func: Func, arg: u8, (x, y): (usize, usize)) -> Arc<Tile> {
+        let reach = match func {
+            Func::Gauss => gaussian_radius(f64::from(arg)),
+            _ => usize::from(arg),
+        };
+        let side = TILE.max(2 * reach);
+        let (width, height) = self.input.dimensions();
+        let (x, y) = (x / side * side, y / side * side);
+        let area = Area {
+            x,
+            y,
+            width: side.min(width as usize - x),
+            height: side.min(height as usize - y),
+        };
+
+        let mut tiles = self.tiles.lock().unwrap();
+        tiles.clock += 1;
+        let clock = tiles.clock;
+        let key = (func, arg, x, y);
+        if let Some((tile, used)) = tiles.tiles.get_mut(&key) {
+            *used = clock;
+            return Arc::clone(tile);
+        }
+        let tile = Arc::new(Tile {
+            area,
+            values: OnceLock::new(),
+        });
+        tiles.bytes += area.width * area.height * 3;
+        tiles.tiles.insert(key, (Arc::clone(&tile), clock));
+        // Tiles still being read stay alive with their readers.
+        while tiles.bytes > self.tile_budget && tiles.tiles.len() > 1 {
+            let (&oldest, _) = tiles
+                .tiles
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .unwrap();
+            let (old, _) = tiles.tiles.remove(&oldest).unwrap();
+            tiles.bytes -= old.area.width * old.area.height * 3;
+        }
+        tile
+    }
+
+    /// The plane of `func` for `arg`, `None` when it is not kept.
+    fn plane(&self, func: Func, arg: u8) -> Option<&[[u8; 3]]> {
+        let kernel = match func {
+            Func::Blur => 0,
+            Func::Edge => 1,
+            Func::High => 2,
+            Func::Low => 3,
+            Func::Gauss => 4,
+            Func::Sobel => 5,
+            Func::Angle => 6,
+            Func::Laplace => 7,
+            _ => unreachable!("{} has no plane of its own", func.name()),
+        };
+        let plane = &self.planes[kernel * VALUES + usize::from(arg)];
+        if let Some(plane) = plane.get() {
+            return Some(plane);
+        }
+        // The filters without an argument have a single plane, always kept.
+        let (width, height) = self.input.dimensions();
+        let bytes = match func {
+            Func::Sobel | Func::Angle | Func::Laplace => 0,
+            _ => width as usize * height as usize * 3,
+        };
+        if self.cached.fetch_add(bytes, Ordering::Relaxed) + bytes > self.budget {
+            self.cached.fetch_sub(bytes, Ordering::Relaxed);
+            return None;
+        }
+        let mut computed = false;
+        let plane = plane.get_or_init(|| {
+            computed = true;
+            compute(
+                self.input,
+                self.edge,
+                func,
+                arg,
+                self.pixels(),
+                Area::whole(self.input),
+            )
+        });
+        // Another thread got there first.
+        if !computed {
+            self.cached.fetch_sub(bytes, Ordering::Relaxed);
+        }
+        Some(plane)
+    }
+}
+
+/// A rectangle of the image, all of it for a plane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Area {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Area {
+    fn whole(input: &DynamicImage) -> Self {
+        Area {
+            x: 0,
+            y: 0,
+            width: input.width() as usize,
+            height: input.height() as usize,
+        }
+    }
+
+    /// The values of the pixels in the area, out of `values` for the whole image.
+    fn pick<T: Copy>(&self, values: &[T], image_width: usize) -> Vec<T> {
+        let rows = values
+            .chunks_exact(image_width)
+            .skip(self.y)
+            .take(self.height);
+        rows.flat_map(|row| &row[self.x..self.x + self.width])
+            .copied()
+            .collect()
+    }
+}
+
+/// A part of a plane, computed by the first pixel reading it.
+struct Tile {
+    area: Area,
+    values: OnceLock<Vec<[u8; 3]>>,
+}
+
+/// The kernel, argument and corner of a tile.
+type TileKey = (Func, u8, usize, usize);
+
+/// The tiles kept by [`Kernels`].
+#[derive(Default)]
+struct Tiles {
+    /// Along with when each tile was last read.
+    tiles: HashMap<TileKey, (Arc<Tile>, u64)>,
+    bytes: usize,
+    clock: u64,
+}
+
+/// `v` plus `amount` percent of how much it stands out from `blurred`.
+fn unsharp(v: u8, blurred: u8, amount: u8) -> u8 {
+    let detail = i32::from(v) - i32::from(blurred);
+    (i32::from(v) + detail * i32::from(amount) / 100).clamp(0, 255) as u8
+}
+
+/// One channel of an area of the image with `r` pixels added on each side, read
+/// through the edge mode. Transparent pixels are `fill` and have to be corrected
+/// for by the caller.
+struct Padded {
+    width: usize,
+    height: usize,
+    values: Vec<u8>,
+}
+
+impl Padded {
+    /// `pixels` are the colors of `input`, row by row.
+    fn new(
+        input: &DynamicImage,
+        pixels: &[[u8; 3]],
+        edge: EdgeMode,
+        r: usize,
+        channel: usize,
+        fill: u8,
+        area: Area,
+    ) -> Self {
+        let (width, height) = input.dimensions();
+        // Where each padded column and row reads from, `None` outside of the image.
+        let axis = |start: usize, count: usize, len: u32| -> Vec<Option<usize>> {
+            (start..start + count + 2 * r)
+                .map(|c| edge.resolve(c as i64 - r as i64, len).map(|c| c as usize))
+                .collect()
+        };
+        let xs = axis(area.x, area.width, width);
+        let ys = axis(area.y, area.height, height);
+        let outside = if edge == EdgeMode::Transparent {
+            fill
+        } else {
+            0
+        };
+
+        let mut values = Vec::with_capacity(xs.len() * ys.len());
+        for y in &ys {
+            for x in &xs {
+                values.push(match (x, y) {
+                    (Some(x), Some(y)) => pixels[y * width as usize + x][channel],
+                    _ => outside,
+                });
+            }
+        }
+        Padded {
+            width: xs.len(),
+            height: ys.len(),
+            values,
+        }
+    }
+}
+
+/// Sums of any rectangle of a [`Padded`] channel in constant time. The sums wrap,
+/// which still gives the right difference for boxes summing to less than 2^32.
+struct SummedArea {
+    width: usize,
+    sums: Vec<u32>,
+}
+
+impl SummedArea {
+    fn new(padded: &Padded) -> Self {
+        let width = padded.width + 1;
+        let mut sums = vec![0u32; width * (padded.height + 1)];
+        for y in 0..padded.height {
+            let mut row = 0u32;
+            for x in 0..padded.width {
+                row = row.wrapping_add(u32::from(padded.values[y * padded.width + x]));
+                sums[(y + 1) * width + x + 1] = sums[y * width + x + 1].wrapping_add(row);
+            }
+        }
+        SummedArea { width, sums }
+    }
+
+    /// Sum of the columns `x0..x1` and rows `y0..y1`.
+    fn sum(&self, (x0, x1): (usize, usize), (y0, y1): (usize, usize)) -> u32 {
+        let at = |x: usize, y: usize| self.sums[y * self.width + x];
+        at(x1, y1)
+            .wrapping_sub(at(x0, y1))
+            .wrapping_sub(at(x1, y0))
+            .wrapping_add(at(x0, y0))
+    }
+}
+
+/// Sums the boxes of `blur(r)` and `edge(r)` in a channel of an area, padded by
+/// `r` pixels.
+struct Boxes<'a> {
+    table: &'a SummedArea,
+    area: Area,
+    /// Of the whole image.
+    size: (usize, usize),
+    transparent: bool,
+}
+
+impl Boxes<'_> {
+    /// `blur(r)` or `edge(r)` at `(x, y)` of the area, whose component is `center`.
+    fn at(&self, func: Func, r: usize, (x, y): (usize, usize), center: u8) -> u8 {
+        let (width, height) = self.size;
+        let (pad, ri) = (r as i64, r as i64);
+        let center = u32::from(center);
+        // Transparent pixels take the value of the center, so a sum gains the
+        // center once for each of them.
+        let sum = |dx: (i64, i64), dy: (i64, i64)| {
+            let cols = (
+                (x as i64 + pad + dx.0) as usize,
+                (x as i64 + pad + dx.1 + 1) as usize,
+            );
+            let rows = (
+                (y as i64 + pad + dy.0) as usize,
+                (y as i64 + pad + dy.1 + 1) as usize,
+            );
+            let outside = match self.transparent {
+                true => {
+                    let (w, h) = ((dx.1 - dx.0 + 1) as u32, (dy.1 - dy.0 + 1) as u32);
+                    let (x, y) = ((self.area.x + x) as i64, (self.area.y + y) as i64);
+                    w * h - inside(x + dx.0, x + dx.1, width) * inside(y + dy.0, y + dy.1, height)
+                }
+                false => 0,
+            };
+            self.table
+                .sum(cols, rows)
+                .wrapping_add(outside.wrapping_mul(center))
+        };
+        match func {
+            Func::Blur if r == 0 => center as u8,
+            Func::Edge if r == 0 => 0,
+            Func::Blur => {
+                let area = ((2 * r + 1) * (2 * r + 1)) as u32;
+                ((sum((-ri, ri), (-ri, ri)) - center) / area) as u8
+            }
+            _ => {
+                let right = sum((1, ri), (-ri, ri)).wrapping_add(sum((0, 0), (1, ri)));
+                let left = sum((-ri, -1), (-ri, ri)).wrapping_add(sum((0, 0), (-ri, -1)));
+                right.wrapping_sub(left) as u8
+            }
+        }
+    }
+}
+
+/// `out[i]` is `f` over `values[i..i + len]`, for every window that fits, by van
+/// Herk/Gil-Werman: with blocks of `len` values, a window is the end of one block
+/// and the start of the next.
+fn running(values: &[u8], len: usize, f: fn(u8, u8) -> u8) -> Vec<u8> {
+    if len == 1 {
+        return values.to_vec();
+    }
+    let mut prefix = values.to_vec();
+    let mut suffix = values.to_vec();
+    for block in prefix.chunks_mut(len) {
+        for i in 1..block.len() {
+            block[i] = f(block[i - 1], block[i]);
+        }
+    }
+    for block in suffix.chunks_mut(len) {
+        for i in (1..block.len()).rev() {
+            block[i - 1] = f(block[i], block[i - 1]);
+        }
+    }
+    suffix
+        .iter()
+        .zip(&prefix[len - 1..])
+        .map(|(a, b)| f(*a, *b))
+        .collect()
+}
+
+/// [`running`] down the columns of `rows`, a window being `len` whole rows, so the
+/// image is read row by row.
+fn running_rows(rows: &[Vec<u8>], len: usize, f: fn(u8, u8) -> u8) -> Vec<Vec<u8>> {
+    if len == 1 {
+        return rows.to_vec();
+    }
+    let combine = |a: &mut [u8], b: &[u8]| a.iter_mut().zip(b).for_each(|(a, b)| *a = f(*a, *b));
+    let mut prefix = rows.to_vec();
+    let mut suffix = rows.to_vec();
+    for block in prefix.chunks_mut(len) {
+        for i in 1..block.len() {
+            let (done, rest) = block.split_at_mut(i);
+            combine(&mut rest[0], &done[i - 1]);
+        }
+    }
+    for block in suffix.chunks_mut(len) {
+        for i in (1..block.len()).rev() {
+            let (rest, done) = block.split_at_mut(i);
+            combine(&mut rest[i - 1], &done[0]);
+        }
+    }
+    suffix
+        .into_iter()
+        .zip(&prefix[len - 1..])
+        .map(|(mut a, b)| {
+            combine(&mut a, b);
+            a
+        })
+        .collect()
+}
+
+/// `f` over the box of radius `r` around every pixel, leaving out the pixel itself.
+/// The box is the rows above, the rows below and the pixels to either side.
+fn extreme(padded: &Padded, r: usize, f: fn(u8, u8) -> u8) -> Vec<u8> {
+    let (width, height) = (padded.width - 2 * r, padded.height - 2 * r);
+    let rows: Vec<&[u8]> = padded.values.chunks_exact(padded.width).collect();
+
+    // Each padded row over the full width of the box, then `r` of those rows.
+    let wide: Vec<Vec<u8>> = rows.iter().map(|row| running(row, 2 * r + 1, f)).collect();
+    let tall = running_rows(&wide, r, f);
+
+    let mut out = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let sides = running(rows[y + r], r, f);
+        for x in 0..width {
+            let above = tall[y][x];
+            let below = tall[y + r + 1][x];
+            out.push(f(f(above, below), f(sides[x], sides[x + r + 1])));
+        }
+    }
+    out
+}
+
+/// How many of the coordinates `lo..=hi` fall inside an axis of `len` pixels.
+fn inside(lo: i64, hi: i64, len: usize) -> u32 {
+    (hi.min(len as i64 - 1) - lo.max(0) + 1).max(0) as u32
+}
+
+fn compute(
+    input: &DynamicImage,
+    edge: EdgeMode,
+    func: Func,
+    arg: u8,
+    centers: &[[u8; 3]],
+    area: Area,
+) -> Vec<[u8; 3]> {
+    match func {
+        Func::Gauss => gaussian(input, edge, f64::from(arg), centers, area),
+        Func::Sobel | Func::Angle | Func::Laplace => gradient(input, edge, func, centers),
+        _ => boxed(input, edge, func, usize::from(arg), centers, area),
+    }
+}
+
+/// Puts together the channels computed one at a time.
+fn per_channel(len: usize, mut f: impl FnMut(usize) -> Vec<u8>) -> Vec<[u8; 3]> {
+    let mut out = vec![[0u8; 3]; len];
+    for channel in 0..3 {
+        for (pixel, v) in out.iter_mut().zip(f(channel)) {
+            pixel[channel] = v;
+        }
+    }
+    out
+}
+
+fn boxed(
+    input: &DynamicImage,
+    edge: EdgeMode,
+    func: Func,
+    r: usize,
+    pixels: &[[u8; 3]],
+    area: Area,
+) -> Vec<[u8; 3]> {
+    let size = (input.width() as usize, input.height() as usize);
+    let centers = area.pick(pixels, size.0);
+    if r == 0 {
+        return match func {
+            Func::Edge => vec![[0; 3]; centers.len()],
+            _ => centers,
+        };
+    }
+
+    // Transparent pixels take the value of the center, so an extreme includes it
+    // when there is any.
+    let transparent = edge == EdgeMode::Transparent;
+    let ri = r as i64;
+    let outside = |i: usize| {
+        let (x, y) = (
+            (area.x + i % area.width) as i64,
+            (area.y + i / area.width) as i64,
+        );
+        inside(x - ri, x + ri, size.0) * inside(y - ri, y + ri, size.1)
+            < ((2 * r + 1) * (2 * r + 1)) as u32
+    };
+
+    per_channel(centers.len(), |channel| {
+        let fill = if func == Func::Low { 255 } else { 0 };
+        let padded = Padded::new(input, pixels, edge, r, channel, fill, area);
+        match func {
+            Func::Blur | Func::Edge => {
+                let boxes = Boxes {
+                    table: &SummedArea::new(&padded),
+                    area,
+                    size,
+                    transparent,
+                };
+                let width = area.width;
+                (0..centers.len())
+                    .map(|i| boxes.at(func, r, (i % width, i / width), centers[i][channel]))
+                    .collect()
+            }
+            _ => {
+                let f = if func == Func::High { u8::max } else { u8::min };
+                let mut values = extreme(&padded, r, f);
+                if transparent {
+                    for (i, v) in values.iter_mut().enumerate() {
+                        if outside(i) {
+                            *v = f(*v, centers[i][channel]);
+                        }
+                    }
+                }
+                values
+            }
+        }
+    })
+}
+
+/// Gaussian blur with a deviation of `sigma` pixels, cut off at three deviations.
+/// The kernel is separable, so rows are blurred and then columns.
+fn gaussian(
+    input: &DynamicImage,
+    edge: EdgeMode,
+    sigma: f64,
+    pixels: &[[u8; 3]],
+    area: Area,
+) -> Vec<[u8; 3]> {
+    let centers = area.pick(pixels, input.width() as usize);
+    if sigma == 0.0 {
+        return centers;
+    }
+    let (width, height) = (area.width, area.height);
+    let (r, weights) = gaussian_weights(sigma);
+
+    // With a transparent edge the weight falling outside of the image goes to the
+    // center.
+    let inside = |start: usize, count: usize, len: u32| -> Vec<f64> {
+        (start..start + count)
+            .map(|p| inside_weight(p, len as usize, r, &weights))
+            .collect()
+    };
+    let inside_x = inside(area.x, width, input.width());
+    let inside_y = inside(area.y, height, input.height());
+
+    per_channel(width * height, |channel| {
+        let padded = Padded::new(input, pixels, edge, r, channel, 0, area);
+        let mut rows = Vec::with_capacity(padded.height * width);
+        for row in padded.values.chunks_exact(padded.width) {
+            for x in 0..width {
+                let cells = weights.iter().zip(&row[x..]);
+                rows.push(cells.map(|(w, v)| w * f64::from(*v)).sum::<f64>());
+            }
+        }
+
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let cells = weights
+                    .iter()
+                    .zip(rows[y * width + x..].iter().step_by(width));
+                let mut v: f64 = cells.map(|(w, v)| w * v).sum();
+                if edge == EdgeMode::Transparent {
+                    let outside = 1.0 - inside_x[x] * inside_y[y];
+                    v += outside * f64::from(centers[y * width + x][channel]);
+                }
+                values.push(v.round() as u8);
+            }
+        }
+        values
+    })
+}
+
+/// The radius of a Gaussian with a deviation of `sigma` and its weights, from `-r`
+/// to `r`, adding up to 1.
+fn gaussian_weights(sigma: f64) -> (usize, Vec<f64>) {
+    let r = gaussian_radius(sigma);
+    let weights: Vec<f64> = (0..=2 * r)
+        .map(|k| (k as f64 - r as f64) / sigma)
+        .map(|d| (-d * d / 2.0).exp())
+        .collect();
+    let total: f64 = weights.iter().sum();
+    (r, weights.iter().map(|w| w / total).collect())
+}
+
+/// How far the Gaussian with a deviation of `sigma` reaches.
+fn gaussian_radius(sigma: f64) -> usize {
+    (3.0 * sigma).ceil() as usize
+}
+
+/// The part of the weights around `p` falling inside an axis of `len` pixels.
+fn inside_weight(p: usize, len: usize, r: usize, weights: &[f64]) -> f64 {
+    let cells = (p as i64 - r as i64..).zip(weights);
+    cells
+        .filter(|(q, _)| (0..len as i64).contains(q))
+        .map(|(_, w)| w)
+        .sum()
+}
+
+/// The image convolved with a `kernel` declaration, reading the pixels under the
+/// weights through the edge mode.
+fn convolve(
+    input: &DynamicImage,
+    edge: EdgeMode,
+    kernel: &Convolution,
+    centers: &[[u8; 3]],
+) -> Vec<[u8; 3]> {
+    let (width, height) = (input.width() as usize, input.height() as usize);
+    let (rx, ry) = (kernel.weights[0].len() / 2, kernel.weights.len() / 2);
+    let r = rx.max(ry);
+    let cells: Vec<(usize, usize, i32)> = kernel
+        .weights
+        .iter()
+        .enumerate()
+        .flat_map(|(dy, row)| {
+            let row = row.iter().enumerate();
+            row.map(move |(dx, w)| (r - rx + dx, r - ry + dy, i32::from(*w)))
+        })
+        .filter(|(_, _, w)| *w != 0)
+        .collect();
+
+    per_channel(width * height, |channel| {
+        let area = Area::whole(input);
+        let padded = Padded::new(input, centers, edge, r, channel, 0, area);
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let center = i32::from(padded.values[(y + r) * padded.width + x + r]);
+                let sum: i32 = cells
+                    .iter()
+                    .map(|&(dx, dy, w)| {
+                        let (px, py) = ((x + dx) as i64 - r as i64, (y + dy) as i64 - r as i64);
+                        let outside =
+                            !(0..width as i64).contains(&px) || !(0..height as i64).contains(&py);
+                        w * match edge == EdgeMode::Transparent && outside {
+                            true => center,
+                            false => i32::from(padded.values[(y + dy) * padded.width + x + dx]),
+                        }
+                    })
+                    .sum();
+                let v = sum.div_euclid(i32::from(kernel.divisor));
+                values.push(match kernel.overflow {
+                    Overflow::Wrap => v.rem_euclid(256) as u8,
+                    Overflow::Clamp => v.clamp(0, 255) as u8,
+                });
+            }
+        }
+        values
+    })
+}
+
+/// The Sobel gradient and the Laplacian, from the 3x3 box around each pixel.
+fn gradient(input: &DynamicImage, edge: EdgeMode, func: Func, centers: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    let (width, height) = (input.width() as usize, input.height() as usize);
+    per_channel(width * height, |channel| {
+        let padded = Padded::new(input, centers, edge, 1, channel, 0, Area::whole(input));
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let center = centers[y * width + x][channel];
+                let p = |dx: usize, dy: usize| -> f64 {
+                    let (px, py) = ((x + dx) as i64 - 1, (y + dy) as i64 - 1);
+                    let outside =
+                        !(0..width as i64).contains(&px) || !(0..height as i64).contains(&py);
+                    match edge == EdgeMode::Transparent && outside {
+                        true => f64::from(center),
+                        false => f64::from(padded.values[(y + dy) * padded.width + x + dx]),
+                    }
+                };
+
+                let gx = p(2, 0) + 2.0 * p(2, 1) + p(2, 2) - p(0, 0) - 2.0 * p(0, 1) - p(0, 2);
+                let gy = p(0, 2) + 2.0 * p(1, 2) + p(2, 2) - p(0, 0) - 2.0 * p(1, 0) - p(2, 0);
+                values.push(match func {
+                    Func::Sobel => gx.hypot(gy) as u8,
+                    // Right is 0 and down, the way y grows, is 64.
+                    Func::Angle if gx == 0.0 && gy == 0.0 => 0,
+                    Func::Angle => {
+                        let turns = gy.atan2(gx) / std::f64::consts::TAU;
+                        ((turns * 256.0).round() as i64).rem_euclid(256) as u8
+                    }
+                    _ => {
+                        let sum = p(0, 1) + p(2, 1) + p(1, 0) + p(1, 2);
+                        (sum - 4.0 * f64::from(center)).abs() as u8
+                    }
+                });
+            }
+        }
+        values
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::image;
+    use image::{Rgba, RgbaImage};
+
+    /// Each kernel straight from its definition, reading every pixel of the box.
+    fn brute(
+        img: &DynamicImage,
+        edge: EdgeMode,
+        func: Func,
+        r: i64,
+        (x, y): (u32, u32),
+    ) -> [u8; 3] {
+        let center = img.get_pixel(x, y);
+        let read = |dx: i64, dy: i64| {
+            let p = (i64::from(x) + dx, i64::from(y) + dy);
+            edge.read(img, p).unwrap_or(center).0
+        };
+        [0, 1, 2].map(|c| {
+            let cells = (-r..=r).flat_map(|dx| (-r..=r).map(move |dy| (dx, dy)));
+            let neighbors: Vec<(i64, i64)> = cells.filter(|d| *d != (0, 0)).collect();
+            let values = neighbors.iter().map(|(dx, dy)| read(*dx, *dy)[c]);
+            match func {
+                Func::Blur => {
+                    let sum: u32 = values.map(u32::from).sum();
+                    (sum / ((2 * r + 1) * (2 * r + 1)) as u32) as u8
+                }
+                Func::High => values.max().unwrap(),
+                Func::Low => values.min().unwrap(),
+                _ => neighbors.iter().fold(0u8, |acc, &(dx, dy)| {
+                    let v = read(dx, dy)[c];
+                    match dx > 0 || dx == 0 && dy > 0 {
+                        true => acc.wrapping_add(v),
+                        false => acc.wrapping_sub(v),
+                    }
+                }),
+            }
+        })
+    }
+
+    #[test]
+    fn test_running_windows() {
+        let values = [3, 1, 4, 1, 5, 9, 2, 6, 5];
+        for len in 1..=values.len() {
+            let expected: Vec<u8> = values
+                .windows(len)
+                .map(|w| *w.iter().max().unwrap())
+                .collect();
+            assert_eq!(running(&values, len, u8::max), expected, "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_same_as_brute_force() {
+        let img = image(9, 6);
+        for edge in [
+            EdgeMode::Zero,
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+            EdgeMode::Transparent,
+        ] {
+            let kernels = Kernels::new(&img, edge);
+            for func in [Func::Blur, Func::Edge, Func::High, Func::Low] {
+                for r in [1, 2, 4, 11] {
+                    for (x, y, _) in img.pixels() {
+                        assert_eq!(
+                            kernels.at(func, &[[r; 3]], (x, y)),
+                            brute(&img, edge, func, i64::from(r), (x, y)),
+                            "{:?} {:?} radius {} at {:?}",
+                            edge,
+                            func,
+                            r,
+                            (x, y)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_radius_per_component() {
+        let img = image(5, 5);
+        let kernels = Kernels::new(&img, EdgeMode::Clamp);
+        let [r, _, _] = kernels.at(Func::High, &[[2; 3]], (1, 3));
+        let [_, g, _] = kernels.at(Func::High, &[[0; 3]], (1, 3));
+        let [_, _, b] = kernels.at(Func::High, &[[7; 3]], (1, 3));
+        assert_eq!(kernels.at(Func::High, &[[2, 0, 7]], (1, 3)), [r, g, b]);
+        assert_eq!(kernels.at(Func::Blur, &[[0; 3]], (1, 3)), [70, 18, 180]);
+        assert_eq!(kernels.at(Func::Edge, &[[0; 3]], (1, 3)), [0; 3]);
+    }
+
+    #[test]
+    fn test_uncached_same_as_planes() {
+        let img = image(9, 6);
+        for edge in [
+            EdgeMode::Zero,
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+            EdgeMode::Transparent,
+        ] {
+            let planes = Kernels::new(&img, edge);
+            let mut direct = Kernels::new(&img, edge);
+            direct.budget = 0;
+            for (func, args) in [
+                (Func::Blur, [[0, 1, 2], [3, 12, 255]]),
+                (Func::Edge, [[0, 1, 2], [3, 12, 255]]),
+                (Func::High, [[0, 1, 2], [3, 12, 30]]),
+                (Func::Low, [[0, 1, 2], [3, 12, 30]]),
+                (Func::Gauss, [[0, 1, 2], [3, 1, 0]]),
+                (Func::Unsharp, [[0, 1, 2], [80, 100, 200]]),
+            ] {
+                for (x, y, _) in img.pixels() {
+                    assert_eq!(
+                        direct.at(func, &args, (x, y)),
+                        planes.at(func, &args, (x, y)),
+                        "{:?} {:?} at {:?}",
+                        edge,
+                        func,
+                        (x, y)
+                    );
+                }
+            }
+            for var in [Var::Blur, Var::Edge, Var::High, Var::Low] {
+                assert_eq!(
+                    direct.var(var, (8, 0)),
+                    planes.var(var, (8, 0)),
+                    "{:?}",
+                    var
+                );
+            }
+            assert!(direct.planes.iter().all(|plane| plane.get().is_none()));
+            assert_eq!(direct.cached.load(Ordering::Relaxed), 0);
+        }
+
+        // A large radius takes tiles twice as wide as it reaches, and the tiles of
+        // each kernel in turn make room for the next.
+        let img = image(150, 40);
+        for edge in [EdgeMode::Clamp, EdgeMode::Transparent] {
+            let planes = Kernels::new(&img, edge);
+            let mut direct = Kernels::new(&img, edge);
+            direct.budget = 0;
+            direct.tile_budget = 150 * 40 * 3;
+            for (func, arg, side) in [
+                (Func::Blur, 3, TILE),
+                (Func::Blur, 70, 140),
+                (Func::High, 40, 80),
+                (Func::Low, 40, 80),
+                (Func::Gauss, 14, 84),
+            ] {
+                let args = [[arg; 3]];
+                for (x, y, _) in img.pixels() {
+                    assert_eq!(
+                        direct.at(func, &args, (x, y)),
+                        planes.at(func, &args, (x, y)),
+                        "{:?} {:?}({}) at {:?}",
+                        edge,
+                        func,
+                        arg,
+                        (x, y)
+                    );
+                }
+                let tile = direct.tile(func, arg, (side + 1, 0));
+                let width = side.min(150 - side);
+                assert_eq!(
+                    tile.area,
+                    Area {
+                        x: side,
+                        y: 0,
+                        width,
+                        height: 40
+                    }
+                );
+                assert!(direct.tiles.lock().unwrap().bytes <= direct.tile_budget);
+            }
+            assert!(direct.planes.iter().all(|plane| plane.get().is_none()));
+        }
+    }
+
+    #[test]
+    fn test_gaussian_is_a_convolution() {
+        let img = image(8, 7);
+        for edge in [EdgeMode::Zero, EdgeMode::Mirror, EdgeMode::Transparent] {
+            let kernels = Kernels::new(&img, edge);
+            for sigma in [1, 2] {
+                let r = 3 * sigma;
+                let weight = |d: i64| (-(d * d) as f64 / (2 * sigma * sigma) as f64).exp();
+                let total: f64 = (-r..=r).map(weight).sum();
+                for (x, y, center) in img.pixels() {
+                    let expected = [0, 1, 2].map(|c| {
+                        let cells = (-r..=r).flat_map(|dx| (-r..=r).map(move |dy| (dx, dy)));
+                        let sum: f64 = cells
+                            .map(|(dx, dy)| {
+                                let p = (i64::from(x) + dx, i64::from(y) + dy);
+                                let v = edge.read(&img, p).unwrap_or(center).0[c];
+                                weight(dx) * weight(dy) * f64::from(v)
+                            })
+                            .sum();
+                        (sum / total / total).round() as i32
+                    });
+                    let found = kernels.at(Func::Gauss, &[[sigma as u8; 3]], (x, y));
+                    for (found, expected) in found.iter().zip(expected) {
+                        assert!((i32::from(*found) - expected).abs() <= 1, "{:?}", edge);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gradients() {
+        // Dark on the left and top, bright on the right and bottom.
+        let mut img = RgbaImage::new(6, 6);
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            *p = Rgba([
+                if x < 3 { 0 } else { 100 },
+                if y < 3 { 0 } else { 100 },
+                50,
+                255,
+            ]);
+        }
+        let img = DynamicImage::from(img);
+        let kernels = Kernels::new(&img, EdgeMode::Clamp);
+
+        assert_eq!(kernels.at(Func::Sobel, &[], (0, 0)), [0, 0, 0]);
+        assert_eq!(kernels.at(Func::Sobel, &[], (2, 1)), [255, 0, 0]);
+        assert_eq!(kernels.at(Func::Angle, &[], (3, 2)), [0, 64, 0]);
+        assert_eq!(kernels.at(Func::Laplace, &[], (3, 3)), [100, 100, 0]);
+        assert_eq!(kernels.at(Func::Laplace, &[], (5, 5)), [0, 0, 0]);
+
+        let kernels = Kernels::new(&img, EdgeMode::Zero);
+        assert_eq!(kernels.at(Func::Angle, &[], (5, 2)), [128, 77, 128]);
+    }
+
+    #[test]
+    fn test_unsharp() {
+        assert_eq!(unsharp(128, 100, 100), 156);
+        assert_eq!(unsharp(128, 100, 50), 142);
+        assert_eq!(unsharp(100, 128, 100), 72);
+        assert_eq!(unsharp(250, 100, 200), 255);
+        assert_eq!(unsharp(10, 100, 100), 0);
+
+        let img = image(5, 4);
+        let kernels = Kernels::new(&img, EdgeMode::Clamp);
+        let blurred = kernels.at(Func::Gauss, &[[2; 3]], (2, 1));
+        let [r, g, b, _] = img.get_pixel(2, 1).0;
+        let expected = [r, g, b]
+            .iter()
+            .zip(blurred)
+            .map(|(v, blurred)| unsharp(*v, blurred, 100))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kernels.at(Func::Unsharp, &[[2; 3]], (2, 1)).to_vec(),
+            expected
+        );
+        assert_eq!(kernels.at(Func::Unsharp, &[[0; 3]], (2, 1)), [r, g, b]);
+    }
+
+    #[test]
+    fn test_convolutions() {
+        let img = image(7, 5);
+        let kernel = |weights: Vec<Vec<i16>>, divisor, overflow| Convolution {
+            name: "k".to_string(),
+            weights,
+            divisor,
+            overflow,
+            span: crate::ast::Span::new(0, 0),
+        };
+        let kernels = [
+            kernel(
+                vec![vec![0, 0, 0], vec![0, 1, 0], vec![0, 0, 0]],
+                1,
+                Overflow::Wrap,
+            ),
+            kernel(
+                vec![vec![0, -1, 0], vec![-1, 5, -1], vec![0, -1, 0]],
+                1,
+                Overflow::Clamp,
+            ),
+            kernel(
+                vec![vec![0, -1, 0], vec![-1, 5, -1], vec![0, -1, 0]],
+                1,
+                Overflow::Wrap,
+            ),
+            kernel(vec![vec![1, 2, 3, 4, 5]], 7, Overflow::Wrap),
+            kernel(
+                vec![vec![-3], vec![1], vec![2], vec![0], vec![9]],
+                2,
+                Overflow::Clamp,
+            ),
+        ];
+
+        for edge in [EdgeMode::Zero, EdgeMode::Wrap, EdgeMode::Transparent] {
+            let convolved = Kernels::new(&img, edge).with_convolutions(&kernels);
+            for (slot, kernel) in kernels.iter().enumerate() {
+                let (rx, ry) = (
+                    kernel.weights[0].len() as i64 / 2,
+                    kernel.weights.len() as i64 / 2,
+                );
+                for (x, y, center) in img.pixels() {
+                    let expected = [0, 1, 2].map(|c| {
+                        let mut sum = 0i32;
+                        for (dy, row) in (-ry..).zip(&kernel.weights) {
+                            for (dx, w) in (-rx..).zip(row) {
+                                let p = (i64::from(x) + dx, i64::from(y) + dy);
+                                let v = edge.read(&img, p).unwrap_or(center).0[c];
+                                sum += i32::from(*w) * i32::from(v);
+                            }
+                        }
+                        let v = sum.div_euclid(i32::from(kernel.divisor));
+                        match kernel.overflow {
+                            Overflow::Wrap => v.rem_euclid(256) as u8,
+                            Overflow::Clamp => v.clamp(0, 255) as u8,
+                        }
+                    });
+                    assert_eq!(
+                        convolved.convolved(slot, (x, y)),
+                        expected,
+                        "{} {:?}",
+                        slot,
+                        edge
+                    );
+                }
+            }
+            for (x, y, p) in img.pixels() {
+                assert_eq!(convolved.convolved(0, (x, y)), [p.0[0], p.0[1], p.0[2]]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        let img = image(6, 4);
+        let program =
+            crate::parser::parse("kernel k = [[1, 2, 1]] / 4; [h, v, d] ^ Y + b[1, 0] - L + k")
+                .unwrap();
+        let program = Compiled::new(&program);
+        let kernels = Kernels::new(&img, EdgeMode::Mirror).with_convolutions(&program.kernels);
+        kernels.prepare(&program);
+        assert!(kernels.pixels.get().is_some() && kernels.luma.get().is_some());
+        assert!(
+            kernels.planes[VALUES + 1].get().is_none(),
+            "`e` is not read"
+        );
+        assert!(kernels.convolved[0].get().is_some());
+
+        let program = crate::parser::parse("blur(4) + gauss(2) - sobel() ^ unsharp(3, 50) + H(x)");
+        let program = Compiled::new(&program.unwrap());
+        let kernels = Kernels::new(&img, EdgeMode::Mirror);
+        kernels.prepare(&program);
+        let prepared: Vec<usize> = (0..kernels.planes.len())
+            .filter(|i| kernels.planes[*i].get().is_some())
+            .collect();
+        assert_eq!(prepared, [4, 4 * VALUES + 2, 4 * VALUES + 3, 5 * VALUES]);
+
+        let rgb = |x: u32, y: u32| {
+            let [r, g, b, _] = img.get_pixel(x, y).0;
+            [r, g, b]
+        };
+        for (x, y, _) in img.pixels() {
+            assert_eq!(kernels.var(Var::Color, (x, y)), rgb(x, y));
+            assert_eq!(kernels.var(Var::HFlip, (x, y)), rgb(5 - x, y));
+            assert_eq!(kernels.var(Var::VFlip, (x, y)), rgb(x, 3 - y));
+            assert_eq!(kernels.var(Var::DFlip, (x, y)), rgb(5 - x, 3 - y));
+            let [r, g, b] = rgb(x, y);
+            let luma = f64::from(r) * 0.299 + f64::from(g) * 0.587 + f64::from(b) * 0.0722;
+            assert_eq!(kernels.var(Var::Lum, (x, y)), [luma as u8; 3]);
+            for var in [Var::Blur, Var::Edge, Var::High, Var::Low] {
+                let func = Func::with_radius(var).unwrap();
+                let radius = brute(&img, EdgeMode::Mirror, func, 1, (x, y));
+                assert_eq!(kernels.var(var, (x, y)), radius, "{:?}", var);
+            }
+        }
+    }
+}
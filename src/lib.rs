@@ -0,0 +1,20 @@
+//! Evaluates glitch expressions over images. The binary parses the command line
+//! and reads and writes the files, everything else lives here.
+
+pub mod ast;
+pub mod bounds;
+pub mod compile;
+pub mod edge;
+pub mod error;
+pub mod eval;
+#[cfg(test)]
+mod fixtures;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod kernels;
+pub mod noise;
+pub mod optimize;
+pub mod parser;
+pub mod sample;
+pub mod simd;
+pub mod validate;
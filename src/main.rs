@@ -1,8 +1,6 @@
 use std::io::{BufReader, BufWriter};
 use std::path::{Path, PathBuf};
 
-use crate::eval::EvalContext;
-use crate::parser::Token;
 use clap::Parser;
 use gif::{Encoder, Repeat};
 use image::codecs::gif::GifDecoder;
@@ -10,10 +8,15 @@ use image::io::Reader as ImageReader;
 use image::{
     AnimationDecoder, ColorType, DynamicImage, GenericImage, GenericImageView, ImageDecoder, Pixel,
 };
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
 
-mod bounds;
-mod eval;
-mod parser;
+use glitch::compile::Compiled;
+use glitch::eval::{EvalContext, Machine};
+#[cfg(feature = "jit")]
+use glitch::jit;
+use glitch::{ast, bounds, compile, edge, eval, kernels, optimize, parser, sample, simd};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -29,6 +32,36 @@ struct Args {
     #[arg(short, long)]
     output: Option<String>,
 
+    /// expression syntax, `v2` makes `<` and `>` comparisons instead of shifts
+    #[arg(long, value_enum, default_value_t)]
+    syntax: parser::Syntax,
+
+    /// file of `kernel` declarations every expression can use
+    #[arg(long)]
+    kernels: Option<PathBuf>,
+
+    /// what `b`, `e`, `H`, `L`, `r`, filters, kernels, offsets like `c[dx, dy]` and remapped
+    /// coordinates read outside of the image
+    #[arg(long, value_enum, default_value_t)]
+    edge: edge::EdgeMode,
+
+    /// how expressions of the form `x, y` read the input between pixels
+    #[arg(long, value_enum, default_value_t)]
+    sampling: sample::Sampling,
+
+    /// seed for `N` and `r`, the same seed gives the same output, random if not set
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// what `s` is at the top of a column, `image` carries it over from the previous
+    /// column like go-glitch did
+    #[arg(long, value_enum, default_value_t)]
+    carry: Carry,
+
+    /// number of threads evaluating the image, all cores if not set
+    #[arg(long)]
+    threads: Option<usize>,
+
     /// open the output file after processing
     #[arg(long, default_value = "false")]
     open: bool,
@@ -43,22 +76,55 @@ fn main() -> anyhow::Result<()> {
         return Err(anyhow::anyhow!("File does not exist"));
     }
 
+    let kernels = match &args.kernels {
+        Some(file) => {
+            let source = std::fs::read_to_string(file)?;
+            match parser::parse_kernels(&source, args.syntax) {
+                Ok(kernels) => kernels,
+                Err(err) => {
+                    let file = file.display();
+                    return Err(anyhow::anyhow!("{}: {}", file, err.render(&source)));
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+
     println!("Parsing expressions");
-    let mut parsed: Vec<(String, Vec<Token>)> = vec![];
+    let mut parsed: Vec<Expression> = vec![];
     for e in &args.expressions {
-        let tokens = match parser::shunting_yard(e) {
-            Ok(tokens) => tokens,
-            Err(err) => {
-                println!("Expression: {}", e);
-                println!("{}", err);
-                return Ok(());
-            }
+        let program = match parser::parse_with_kernels(e, args.syntax, &kernels) {
+            Ok(program) => program,
+            Err(err) => return Err(anyhow::anyhow!("{}", err.render(e))),
         };
 
         println!("\tExpression: {:?}", e);
-        println!("\tTokens: {:?}", tokens.clone());
+        println!("\tParsed: {}", program);
+        let program = optimize::optimize(program);
+        println!("\tOptimized: {}", program);
+        println!("\tTokens: {:?}", program.to_rpn());
+
+        let expression = Expression::new(e, &program)?;
+        #[cfg(feature = "jit")]
+        match expression.kernel {
+            Some(_) => println!("\tKernel: native"),
+            None => println!("\tKernel: interpreted"),
+        }
+        parsed.push(expression);
+    }
+
+    let options = Options {
+        edge: args.edge,
+        sampling: args.sampling,
+        seed: args.seed.unwrap_or_else(rand::random),
+        carry: args.carry,
+    };
+    println!("Seed: {}", options.seed);
 
-        parsed.push((e.to_string(), tokens));
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
     }
 
     println!("Consuming expressions");
@@ -78,13 +144,13 @@ fn main() -> anyhow::Result<()> {
         image::ImageFormat::Png => {
             println!("\tProcessing mode: PNG");
 
-            let out = process(img, parsed)?;
+            let out = process(img, &parsed, options, 0)?;
             out.save_with_format(output_file, format)?;
         }
         image::ImageFormat::Jpeg => {
             println!("\tProcessing mode: JPEG");
 
-            let out = process(img, parsed)?;
+            let out = process(img, &parsed, options, 0)?;
             out.save_with_format(output_file, format)?;
         }
         image::ImageFormat::Gif => {
@@ -102,11 +168,11 @@ fn main() -> anyhow::Result<()> {
             let mut encoder = Encoder::new(&mut writer, w as u16, h as u16, &[])?;
             encoder.set_repeat(Repeat::Infinite)?;
 
-            for frame in &frames {
+            for (i, frame) in frames.iter().enumerate() {
                 let frame = frame.clone();
                 let delay = frame.delay().numer_denom_ms().0 as u16;
                 let img = frame.into_buffer();
-                let out = process(img.into(), parsed.clone())?;
+                let out = process(img.into(), &parsed, options, i as u32)?;
                 let mut bytes = out.as_bytes().to_vec();
 
                 let mut new_frame = gif::Frame::from_rgba_speed(w as u16, h as u16, &mut bytes, 10);
@@ -127,51 +193,154 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn process(
-    mut img: DynamicImage,
-    expressions: Vec<(String, Vec<Token>)>,
-) -> anyhow::Result<DynamicImage> {
-    let mut output_image = DynamicImage::new(img.width(), img.height(), ColorType::Rgba8);
-
-    for val in &expressions {
-        let (_, tokens) = val;
-
-        let width = img.width();
-        let height = img.height();
+/// An expression given on the command line, ready to be evaluated.
+struct Expression {
+    source: String,
+    program: Compiled,
+    /// Native code for the expression when it only reads the current pixel.
+    #[cfg(feature = "jit")]
+    kernel: Option<jit::Kernel>,
+}
 
-        let mut sr = 0u8;
-        let mut sg = 0u8;
-        let mut sb = 0u8;
+impl Expression {
+    fn new(source: &str, program: &ast::Program) -> anyhow::Result<Self> {
+        Ok(Expression {
+            source: source.to_string(),
+            program: Compiled::new(program),
+            #[cfg(feature = "jit")]
+            kernel: match jit::supports(program) {
+                // The interpreter gives the same output, only slower.
+                true => match jit::Kernel::new(program) {
+                    Ok(kernel) => Some(kernel),
+                    Err(err) => {
+                        println!(
+                            "\tNative code failed ({}), falling back to the interpreter",
+                            err
+                        );
+                        None
+                    }
+                },
+                false => None,
+            },
+        })
+    }
+}
 
-        let bounds = bounds::find_non_zero_bounds(&img).expect("Failed to find non-zero bounds");
-        let min_x = bounds.min_x();
-        let max_x = bounds.max_x();
+/// Command line settings that apply to every frame.
+#[derive(Debug, Clone, Copy)]
+struct Options {
+    edge: edge::EdgeMode,
+    sampling: sample::Sampling,
+    seed: u64,
+    carry: Carry,
+}
 
-        let min_y = bounds.min_y();
-        let max_y = bounds.max_y();
-        let rng = rand::thread_rng();
+/// What `s` is at the top of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum Carry {
+    /// 0, so the columns can be evaluated in parallel.
+    #[default]
+    Column,
+    /// The result of the bottom of the previous column, like go-glitch. The
+    /// columns of expressions using `s` are evaluated one after the other.
+    Image,
+}
 
-        for x in min_x..max_x {
-            for y in min_y..max_y {
-                let colors = img.get_pixel(x, y).to_rgba();
+fn process(
+    mut img: DynamicImage,
+    expressions: &[Expression],
+    options: Options,
+    frame: u32,
+) -> anyhow::Result<DynamicImage> {
+    let mut output_image = DynamicImage::new(img.width(), img.height(), ColorType::Rgba8);
 
-                let result = eval::eval(
-                    EvalContext {
-                        tokens: tokens.clone(),
-                        size: (width, height),
-                        rgba: colors.0,
-                        saved_rgb: [sr, sg, sb],
-                        position: (x, y),
+    for (index, expression) in expressions.iter().enumerate() {
+        // A fully transparent black image has no pixels to evaluate, it is copied
+        // through as it is.
+        let Some(bounds) = bounds::find_non_zero_bounds(&img) else {
+            output_image = img.clone();
+            break;
+        };
+        // The bounds include their last row and column.
+        let xs = bounds.min_x()..bounds.max_x() + 1;
+        let ys = bounds.min_y()..bounds.max_y() + 1;
+
+        #[cfg(feature = "jit")]
+        if let Some(kernel) = &expression.kernel {
+            run_kernel(kernel, &img, &mut output_image, xs, ys, frame);
+            img = output_image.clone();
+            continue;
+        }
+        let (source, program) = (&expression.source, &expression.program);
+        let kernels = kernels::Kernels::new(&img, options.edge).with_convolutions(&program.kernels);
+        kernels.prepare(program);
+
+        if simd::supports(program) {
+            let len = xs.len();
+            let rows = ys
+                .clone()
+                .into_par_iter()
+                .map_init(
+                    || {
+                        let inputs =
+                            eval::Inputs::new(&img, options.edge, &kernels, options.seed, frame);
+                        (simd::RowMachine::new(program, len), inputs)
                     },
-                    &img,
-                    rng.clone(),
+                    |(machine, inputs), y| machine.eval(program, inputs, &img, y, xs.clone()),
                 )
-                .expect("Failed to evaluate");
+                .collect::<Vec<_>>();
 
-                sr = result[0];
-                sg = result[1];
-                sb = result[2];
+            for (y, row) in ys.clone().zip(rows) {
+                for (x, result) in xs.clone().zip(row) {
+                    output_image.put_pixel(x, y, result);
+                }
+            }
+
+            img = output_image.clone();
+            continue;
+        }
 
+        let column = |x: u32, rng: &mut ChaCha8Rng, saved_rgb: &mut [u8; 3]| {
+            let column = Column {
+                img: &img,
+                program,
+                kernels: &kernels,
+                options,
+                frame,
+                x,
+            };
+            column.eval(ys.clone(), rng, saved_rgb).map_err(|(y, err)| {
+                anyhow::anyhow!("Failed to evaluate {:?} at ({}, {}): {}", source, x, y, err)
+            })
+        };
+        let carried = options.carry == Carry::Image
+            && program.code.contains(&compile::Op::Var(ast::Var::Saved));
+        let columns = match carried {
+            // `s` goes on from the bottom of a column to the top of the next, so the
+            // columns are evaluated in order on a single random stream.
+            true => {
+                let mut rng = frame_rng(options.seed, frame);
+                rng.set_stream((index as u64) << 32);
+                let mut saved_rgb = [0u8; 3];
+                xs.clone()
+                    .map(|x| column(x, &mut rng, &mut saved_rgb))
+                    .collect::<anyhow::Result<Vec<_>>>()?
+            }
+            // Columns are independent, each one starts with `s` at zero and has its
+            // own random stream, so the output does not depend on the thread count.
+            false => xs
+                .clone()
+                .into_par_iter()
+                .map(|x| {
+                    let mut rng = frame_rng(options.seed, frame);
+                    rng.set_stream((index as u64) << 32 | u64::from(x));
+                    column(x, &mut rng, &mut [0; 3])
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        };
+
+        for (x, column) in xs.zip(columns) {
+            for (y, result) in ys.clone().zip(column) {
                 output_image.put_pixel(x, y, result);
             }
         }
@@ -181,6 +350,109 @@ fn process(
     Ok(output_image)
 }
 
+/// The generator `N` and `r` draw from in a frame. The seed and the frame are the
+/// key side by side, so no two pairs of them share random numbers.
+fn frame_rng(seed: u64, frame: u32) -> ChaCha8Rng {
+    let mut key = [0u8; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..12].copy_from_slice(&frame.to_le_bytes());
+    ChaCha8Rng::from_seed(key)
+}
+
+/// Evaluates the pixels in `xs` and `ys` with native code, rows in parallel.
+#[cfg(feature = "jit")]
+fn run_kernel(
+    kernel: &jit::Kernel,
+    img: &DynamicImage,
+    output: &mut DynamicImage,
+    xs: std::ops::Range<u32>,
+    ys: std::ops::Range<u32>,
+    frame: u32,
+) {
+    let size = img.dimensions();
+    let input = match img.as_rgba8() {
+        Some(img) => std::borrow::Cow::Borrowed(img.as_raw()),
+        None => std::borrow::Cow::Owned(img.to_rgba8().into_raw()),
+    };
+    let output = output.as_mut_rgba8().expect("output is RGBA");
+    if ys.is_empty() {
+        return;
+    }
+
+    output
+        .par_chunks_exact_mut(size.0 as usize * 4)
+        .enumerate()
+        .skip(ys.start as usize)
+        .take(ys.len())
+        .for_each(|(y, row)| kernel.run(&input, size, frame, y as u32, xs.clone(), row));
+}
+
+/// A column of the image being evaluated by one expression.
+struct Column<'a> {
+    img: &'a DynamicImage,
+    program: &'a Compiled,
+    kernels: &'a kernels::Kernels<'a>,
+    options: Options,
+    frame: u32,
+    x: u32,
+}
+
+impl Column<'_> {
+    /// Evaluates the pixels of `rows` from top to bottom, `s` being the result of
+    /// the pixel above and `saved_rgb` for the first one. `saved_rgb` is left with
+    /// the result of the last pixel. Errors carry the row they happened at.
+    fn eval(
+        &self,
+        rows: std::ops::Range<u32>,
+        rng: &mut ChaCha8Rng,
+        saved_rgb: &mut [u8; 3],
+    ) -> Result<Vec<image::Rgba<u8>>, (u32, String)> {
+        let Options {
+            edge,
+            sampling,
+            seed,
+            ..
+        } = self.options;
+        let (img, x) = (self.img, self.x);
+        let (width, height) = img.dimensions();
+
+        let mut machine = Machine::new(self.program);
+        let mut out = Vec::with_capacity(rows.len());
+        for y in rows {
+            let colors = img.get_pixel(x, y).to_rgba();
+
+            let ctx = EvalContext {
+                program: self.program,
+                machine: &mut machine,
+                size: (width, height),
+                rgba: colors.0,
+                saved_rgb: *saved_rgb,
+                position: (x, y),
+                edge,
+                kernels: self.kernels,
+                rng: &mut *rng,
+                seed,
+                frame: self.frame,
+            };
+            let result = match self.program.remap {
+                Some(_) => eval::eval_coords(ctx, img).map(|(sx, sy)| {
+                    let source = (
+                        sample::source_coord(x, sx, width),
+                        sample::source_coord(y, sy, height),
+                    );
+                    sample::sample(img, source, sampling, edge, colors)
+                }),
+                None => eval::eval(ctx, img),
+            }
+            .map_err(|err| (y, err))?;
+
+            *saved_rgb = [result[0], result[1], result[2]];
+            out.push(result);
+        }
+        Ok(out)
+    }
+}
+
 fn get_format(file: &Path) -> image::ImageFormat {
     match file
         .extension()
@@ -206,3 +478,234 @@ fn get_output_extension(file: &Path) -> &str {
         .to_str()
         .expect("to string")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_output() {
+        let mut img = image::RgbaImage::new(8, 8);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = image::Rgba([x as u8 * 30, y as u8 * 30, 90, 255]);
+        }
+        let program = parser::parse("(N & c) ^ r").unwrap();
+        let parsed = [Expression::new("(N & c) ^ r", &program).unwrap()];
+
+        let run = |seed: u64, frame: u32| {
+            let options = Options {
+                edge: edge::EdgeMode::Zero,
+                sampling: sample::Sampling::Nearest,
+                seed,
+                carry: Carry::Column,
+            };
+            let out = process(img.clone().into(), &parsed, options, frame).unwrap();
+            out.into_bytes()
+        };
+
+        assert_eq!(run(42, 0), run(42, 0));
+        assert_ne!(run(42, 0), run(43, 0));
+        assert_ne!(run(42, 0), run(42, 1));
+        assert_ne!(run(42, 1), run(43, 0));
+    }
+
+    #[test]
+    fn test_whole_image_is_processed() {
+        let img = image::RgbaImage::from_pixel(5, 4, image::Rgba([10, 20, 30, 255]));
+        let options = Options {
+            edge: edge::EdgeMode::Clamp,
+            sampling: sample::Sampling::Nearest,
+            seed: 0,
+            carry: Carry::Column,
+        };
+        // One runs a row at a time, the other a column at a time.
+        for expr in ["b + 1", "s | 1"] {
+            let parsed = [Expression::new(expr, &parser::parse(expr).unwrap()).unwrap()];
+            let out = process(img.clone().into(), &parsed, options, 0).unwrap();
+            let out = out.as_rgba8().unwrap();
+            assert!(
+                out.pixels().all(|p| p.0[0] != 0 && p.0[3] == 255),
+                "{}",
+                expr
+            );
+        }
+    }
+
+    #[test]
+    fn test_transparent_black_image_is_copied() {
+        let img = image::RgbaImage::new(3, 2);
+        let options = Options {
+            edge: edge::EdgeMode::Clamp,
+            sampling: sample::Sampling::Nearest,
+            seed: 0,
+            carry: Carry::Column,
+        };
+        for expr in ["c + 1", "s | 1"] {
+            let parsed = [Expression::new(expr, &parser::parse(expr).unwrap()).unwrap()];
+            let out = process(img.clone().into(), &parsed, options, 0).unwrap();
+            assert_eq!(out.as_rgba8(), Some(&img), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_bounds_include_last_row_and_column() {
+        let inside = |x: u32, y: u32| (1..=4).contains(&x) && (1..=3).contains(&y);
+        let mut img = image::RgbaImage::new(6, 5);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            if inside(x, y) {
+                *pixel = image::Rgba([10, 20, 30, 255]);
+            }
+        }
+        let options = Options {
+            edge: edge::EdgeMode::Zero,
+            sampling: sample::Sampling::Nearest,
+            seed: 0,
+            carry: Carry::Column,
+        };
+        // Native code with the `jit` feature, a row at a time and a column at a time.
+        for expr in ["c + 1", "c[0, 0] + 1", "c + 1 + (s ? 255)"] {
+            let parsed = [Expression::new(expr, &parser::parse(expr).unwrap()).unwrap()];
+            let out = process(img.clone().into(), &parsed, options, 0).unwrap();
+            for (x, y, pixel) in out.as_rgba8().unwrap().enumerate_pixels() {
+                let expected = match inside(x, y) {
+                    true => [11, 21, 31, 255],
+                    false => [0; 4],
+                };
+                assert_eq!(pixel.0, expected, "{} at ({}, {})", expr, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_carry_saved_across_columns() {
+        let img = image::RgbaImage::from_pixel(3, 2, image::Rgba([10, 20, 30, 255]));
+        let parsed = [Expression::new("s + 1", &parser::parse("s + 1").unwrap()).unwrap()];
+        let run = |carry: Carry| {
+            let options = Options {
+                edge: edge::EdgeMode::Zero,
+                sampling: sample::Sampling::Nearest,
+                seed: 0,
+                carry,
+            };
+            let out = process(img.clone().into(), &parsed, options, 0).unwrap();
+            let out = out.as_rgba8().unwrap().clone();
+            out.enumerate_pixels()
+                .map(|(x, y, p)| ((x, y), p.0[0]))
+                .collect::<Vec<_>>()
+        };
+
+        let column = run(Carry::Column);
+        assert!(column.iter().all(|&((_, y), v)| u32::from(v) == y + 1));
+        let image = run(Carry::Image);
+        assert!(image
+            .iter()
+            .all(|&((x, y), v)| u32::from(v) == 2 * x + y + 1));
+    }
+
+    #[test]
+    fn test_threads_do_not_change_output() {
+        let mut img = image::RgbaImage::new(37, 23);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = image::Rgba([x as u8 * 7, y as u8 * 11, (x * y) as u8, 255]);
+        }
+        let parsed = ["s ^ N + r", "c[1, 1] - s"]
+            .map(|e| Expression::new(e, &parser::parse(e).unwrap()).unwrap());
+        let options = Options {
+            edge: edge::EdgeMode::Zero,
+            sampling: sample::Sampling::Nearest,
+            seed: 9,
+            carry: Carry::Column,
+        };
+
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| process(img.clone().into(), &parsed, options, 0))
+                .unwrap()
+                .into_bytes()
+        };
+
+        let single = run(1);
+        assert_eq!(single, run(4));
+        assert_eq!(single, run(7));
+    }
+
+    #[test]
+    fn test_optimized_output_is_unchanged() {
+        let mut img = image::RgbaImage::new(19, 13);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = image::Rgba([x as u8 * 13, y as u8 * 19, (x ^ y) as u8 * 8, 200]);
+        }
+        let expressions = [
+            "(c + 0) * (b - (h & 255)) + (c + 0) * (b - h) + N * 0",
+            "let k = 3 # 2; [c % k, r : 0, (s ^ s) | v, 255 @ x]",
+            "if y ? (128 - 28) then min(e, e) + r else lerp(c, N, 0)",
+            "c[2 - 1, 0] + c[1, 0] - perlin(16) + perlin(8 + 8)",
+            "let k = N; k * 0 + N",
+            "let k = r; (k & 0) ^ N",
+            "if c ? 100 then N else N",
+        ];
+        let options = Options {
+            edge: edge::EdgeMode::Mirror,
+            sampling: sample::Sampling::Nearest,
+            seed: 5,
+            carry: Carry::Column,
+        };
+
+        let run = |optimized: bool| {
+            let parsed: Vec<_> = expressions
+                .iter()
+                .map(|e| {
+                    let program = parser::parse(e).unwrap();
+                    let program = match optimized {
+                        true => optimize::optimize(program),
+                        false => program,
+                    };
+                    Expression::new(e, &program).unwrap()
+                })
+                .collect();
+            process(img.clone().into(), &parsed, options, 0)
+                .unwrap()
+                .into_bytes()
+        };
+
+        assert_eq!(run(false), run(true));
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_kernels_match_interpreter() {
+        let mut img = image::RgbaImage::new(23, 17);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let inside = (3..20).contains(&x) && (2..15).contains(&y);
+            *pixel = image::Rgba([x as u8 * 11, y as u8 * 13, 7, 255 * u8::from(inside)]);
+        }
+        let expressions = ["c ^ (x + y)", "s + c", "[h - v, d, Y, x]"];
+        let options = Options {
+            edge: edge::EdgeMode::Zero,
+            sampling: sample::Sampling::Nearest,
+            seed: 1,
+            carry: Carry::Column,
+        };
+
+        let run = |native: bool| {
+            let parsed: Vec<_> = expressions
+                .iter()
+                .map(|e| {
+                    let mut expression = Expression::new(e, &parser::parse(e).unwrap()).unwrap();
+                    if !native {
+                        expression.kernel = None;
+                    }
+                    expression
+                })
+                .collect();
+            process(img.clone().into(), &parsed, options, 2)
+                .unwrap()
+                .into_bytes()
+        };
+
+        assert_eq!(run(false), run(true));
+    }
+}
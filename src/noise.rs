@@ -0,0 +1,146 @@
+/// A noise field that only depends on where and when it is sampled, unlike `N`
+/// which draws from the generator and so depends on the order pixels are visited.
+/// Coordinates are `x`, `y` in pixels and the frame as a third axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    key: u64,
+}
+
+impl Field {
+    /// Fields with a different seed or salt are unrelated to each other.
+    pub fn new(seed: u64, salt: &[u64]) -> Self {
+        let key = salt.iter().fold(mix(seed), |key, v| mix(key ^ mix(*v)));
+        Field { key }
+    }
+
+    fn hash(self, [x, y, z]: [i64; 3]) -> u64 {
+        [x, y, z].iter().fold(self.key, |h, v| mix(h ^ *v as u64))
+    }
+
+    /// Independent for every pixel of every frame.
+    pub fn white(self, p: [i64; 3]) -> u8 {
+        (self.hash(p) >> 56) as u8
+    }
+
+    /// Random values at points `scale` pixels apart, smoothly blended in between.
+    pub fn value(self, p: [i64; 3], scale: u8) -> u8 {
+        let n = lattice(p, scale, |corner, _| {
+            (self.hash(corner) >> 11) as f64 / (1u64 << 53) as f64
+        });
+        (n * 255.0).round() as u8
+    }
+
+    /// Perlin gradient noise with features about `scale` pixels wide, centered on 128.
+    pub fn perlin(self, p: [i64; 3], scale: u8) -> u8 {
+        let n = lattice(p, scale, |corner, d| {
+            let [gx, gy, gz] = GRADIENTS[(self.hash(corner) % 12) as usize];
+            gx * d[0] + gy * d[1] + gz * d[2]
+        });
+        ((n + 1.0) * 127.5).round().clamp(0.0, 255.0) as u8
+    }
+}
+
+/// Directions to the middle of the edges of a cube, as used by improved Perlin noise.
+const GRADIENTS: [[f64; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+/// Blends `corner` over the 8 lattice points around `p / scale`. `corner` gets
+/// the lattice point and the offset of `p` from it, in lattice units.
+fn lattice(p: [i64; 3], scale: u8, corner: impl Fn([i64; 3], [f64; 3]) -> f64) -> f64 {
+    let scale = f64::from(scale.max(1));
+    let p = p.map(|v| v as f64 / scale);
+    let cell = p.map(f64::floor);
+    let f = [p[0] - cell[0], p[1] - cell[1], p[2] - cell[2]];
+    let cell = cell.map(|v| v as i64);
+
+    let mut values = [0.0; 8];
+    for (i, value) in values.iter_mut().enumerate() {
+        let bits = [i & 1, (i >> 1) & 1, (i >> 2) & 1];
+        let point = [
+            cell[0] + bits[0] as i64,
+            cell[1] + bits[1] as i64,
+            cell[2] + bits[2] as i64,
+        ];
+        let d = [
+            f[0] - bits[0] as f64,
+            f[1] - bits[1] as f64,
+            f[2] - bits[2] as f64,
+        ];
+        *value = corner(point, d);
+    }
+
+    let [tx, ty, tz] = f.map(fade);
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let x = [
+        lerp(values[0], values[1], tx),
+        lerp(values[2], values[3], tx),
+        lerp(values[4], values[5], tx),
+        lerp(values[6], values[7], tx),
+    ];
+    let y = [lerp(x[0], x[1], ty), lerp(x[2], x[3], ty)];
+    lerp(y[0], y[1], tz)
+}
+
+/// `6t^5 - 15t^4 + 10t^3`, flat at both ends so the blend has no visible seams.
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// The splitmix64 finalizer.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pure_function_of_position() {
+        let field = Field::new(7, &[1]);
+        assert_eq!(field.white([3, 4, 0]), Field::new(7, &[1]).white([3, 4, 0]));
+        assert_eq!(field.perlin([30, 4, 2], 16), field.perlin([30, 4, 2], 16));
+
+        let other = Field::new(8, &[1]);
+        let differs = (0..64).any(|x| field.white([x, 0, 0]) != other.white([x, 0, 0]));
+        assert!(differs);
+    }
+
+    #[test]
+    fn test_value_noise_hits_lattice_values() {
+        let field = Field::new(1, &[]);
+        // On a lattice point the value is the hash of that point, in between it
+        // stays within the range of the surrounding points.
+        let a = field.value([0, 0, 0], 8);
+        let b = field.value([8, 0, 0], 8);
+        assert_eq!(field.value([16, 0, 0], 8), field.value([2, 0, 0], 1));
+        let mid = field.value([4, 0, 0], 8);
+        assert!(a.min(b) <= mid && mid <= a.max(b));
+    }
+
+    #[test]
+    fn test_perlin_is_smooth() {
+        let field = Field::new(3, &[]);
+        assert_eq!(field.perlin([0, 0, 0], 32), 128);
+        for x in 0..255 {
+            let a = i32::from(field.perlin([x, 5, 0], 32));
+            let b = i32::from(field.perlin([x + 1, 5, 0], 32));
+            assert!((a - b).abs() <= 12, "jump between {} and {}", x, x + 1);
+        }
+    }
+}
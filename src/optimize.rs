@@ -0,0 +1,537 @@
+use crate::ast::{BinOp, Binding, Expr, ExprKind, Func, Program, Span, UnOp, Var};
+use crate::eval::{fold_binary, fold_call, fold_unary};
+
+/// Simplifies a program without changing any pixel it produces. Constants are
+/// folded with the same wrapping arithmetic as evaluation, identities like
+/// `c + 0` are removed and pure subexpressions used more than once are moved
+/// into bindings. Nothing containing `N` or `r` is dropped or shared, so the
+/// same random numbers are drawn.
+pub fn optimize(program: Program) -> Program {
+    let Program {
+        kernels,
+        bindings,
+        body,
+        alpha,
+        remap,
+    } = program;
+
+    let mut known = Vec::with_capacity(bindings.len());
+    let mut bindings: Vec<Binding> = bindings
+        .into_iter()
+        .map(|binding| {
+            let value = simplify(binding.value, &known);
+            known.push(Known {
+                value: constant(&value),
+                pure: is_pure(&value, &known),
+            });
+            Binding { value, ..binding }
+        })
+        .collect();
+
+    // A body setting alpha has to stay a channel list to keep the alpha.
+    let mut body = match (body.kind, &alpha) {
+        (ExprKind::Channels(items), Some(_)) => Expr::new(
+            ExprKind::Channels(
+                items
+                    .into_iter()
+                    .map(|item| simplify(item, &known))
+                    .collect(),
+            ),
+            body.span,
+        ),
+        (kind, _) => simplify(Expr::new(kind, body.span), &known),
+    };
+    let mut alpha = alpha.map(|alpha| simplify(alpha, &known));
+    let mut remap = remap.map(|y| simplify(y, &known));
+
+    let mut roots: Vec<&mut Expr> = [Some(&mut body), alpha.as_mut(), remap.as_mut()]
+        .into_iter()
+        .flatten()
+        .collect();
+    share(&mut roots, &mut bindings, &mut known);
+    prune(&mut roots, &mut bindings);
+
+    Program {
+        kernels,
+        bindings,
+        body,
+        alpha,
+        remap,
+    }
+}
+
+/// What is known about a binding when simplifying the code after it.
+#[derive(Debug, Clone, Copy)]
+struct Known {
+    /// The value of a binding folded to a constant.
+    value: Option<u8>,
+    /// Whether evaluating the binding draws no random numbers, see [`is_pure`].
+    pure: bool,
+}
+
+fn simplify(expr: Expr, known: &[Known]) -> Expr {
+    let span = expr.span;
+    match expr.kind {
+        ExprKind::Group(inner) => simplify(*inner, known),
+        ExprKind::Local { slot, name } => match known[slot].value {
+            Some(n) => num(n, span),
+            None => Expr::new(ExprKind::Local { slot, name }, span),
+        },
+        ExprKind::Unary { op, operand } => unary(op, simplify(*operand, known), span),
+        ExprKind::Swizzle { operand, lanes } => swizzle(simplify(*operand, known), lanes, span),
+        ExprKind::Offset { var, dx, dy } => Expr::new(
+            ExprKind::Offset {
+                var,
+                dx: Box::new(simplify(*dx, known)),
+                dy: Box::new(simplify(*dy, known)),
+            },
+            span,
+        ),
+        ExprKind::Binary { op, lhs, rhs } => {
+            let (lhs, rhs) = (simplify(*lhs, known), simplify(*rhs, known));
+            binary(op, lhs, rhs, span, known)
+        }
+        ExprKind::Call { func, args } => {
+            let args = args.into_iter().map(|arg| simplify(arg, known)).collect();
+            call(func, args, span, known)
+        }
+        ExprKind::Cond {
+            cond,
+            if_true,
+            if_false,
+        } => {
+            let cond = simplify(*cond, known);
+            let if_true = simplify(*if_true, known);
+            let if_false = simplify(*if_false, known);
+            match constant(&cond) {
+                Some(0) => if_false,
+                Some(_) => if_true,
+                // Both branches run when the mask is mixed, so the one kept has to
+                // draw no random numbers either.
+                None if is_pure(&cond, known)
+                    && is_pure(&if_true, known)
+                    && same(&if_true, &if_false) =>
+                {
+                    if_true
+                }
+                None => Expr::new(
+                    ExprKind::Cond {
+                        cond: Box::new(cond),
+                        if_true: Box::new(if_true),
+                        if_false: Box::new(if_false),
+                    },
+                    span,
+                ),
+            }
+        }
+        ExprKind::Channels(items) => {
+            let items: Vec<Expr> = items
+                .into_iter()
+                .map(|item| simplify(item, known))
+                .collect();
+            // Taking each component of the same value gives that value back.
+            if is_pure(&items[0], known) && items[1..].iter().all(|item| same(item, &items[0])) {
+                return items.into_iter().next().unwrap();
+            }
+            Expr::new(ExprKind::Channels(items), span)
+        }
+        kind => Expr::new(kind, span),
+    }
+}
+
+fn unary(op: UnOp, operand: Expr, span: Span) -> Expr {
+    if let Some(n) = constant(&operand) {
+        return num(fold_unary(op, n), span);
+    }
+    match operand.kind {
+        // `--c` and `~~c` cancel out, `abs` does not.
+        ExprKind::Unary {
+            op: inner,
+            operand: inner_operand,
+        } if inner == op && op != UnOp::Abs => *inner_operand,
+        kind => Expr::new(
+            ExprKind::Unary {
+                op,
+                operand: Box::new(Expr::new(kind, operand.span)),
+            },
+            span,
+        ),
+    }
+}
+
+fn swizzle(operand: Expr, lanes: [u8; 3], span: Span) -> Expr {
+    if constant(&operand).is_some() || lanes == [0, 1, 2] {
+        return operand;
+    }
+    match operand.kind {
+        ExprKind::Swizzle {
+            operand: inner,
+            lanes: inner_lanes,
+        } => {
+            let lanes = lanes.map(|lane| inner_lanes[lane as usize]);
+            swizzle(*inner, lanes, span)
+        }
+        kind => Expr::new(
+            ExprKind::Swizzle {
+                operand: Box::new(Expr::new(kind, operand.span)),
+                lanes,
+            },
+            span,
+        ),
+    }
+}
+
+fn binary(op: BinOp, lhs: Expr, rhs: Expr, span: Span, known: &[Known]) -> Expr {
+    use BinOp::*;
+
+    let (a, b) = (constant(&lhs), constant(&rhs));
+    if let (Some(a), Some(b)) = (a, b) {
+        return num(fold_binary(op, a, b), span);
+    }
+
+    // The other operand can only be dropped when evaluating it draws no random
+    // numbers.
+    let drop_rhs = |n: u8| match is_pure(&rhs, known) {
+        true => Some(num(n, span)),
+        false => None,
+    };
+    let drop_lhs = |n: u8| match is_pure(&lhs, known) {
+        true => Some(num(n, span)),
+        false => None,
+    };
+    let pure_same = is_pure(&lhs, known) && same(&lhs, &rhs);
+
+    let simplified = match (op, a, b) {
+        (Add | Sub | BitOr | BitXor | BitAndNot | Div | Mod, _, Some(0)) => Some(lhs.clone()),
+        (Add | BitOr | BitXor, Some(0), _) => Some(rhs.clone()),
+        (Mul | Div | Pow, _, Some(1)) | (BitAnd | Weight, _, Some(255)) => Some(lhs.clone()),
+        (Mul, Some(1), _) | (BitAnd, Some(255), _) => Some(rhs.clone()),
+        // Shift amounts wrap at 8, like `u8::wrapping_shl`.
+        (BitLShift | BitRShift, _, Some(n)) if n % 8 == 0 => Some(lhs.clone()),
+        (Mul | BitAnd | Weight, _, Some(0)) | (Mod, _, Some(1)) | (BitAndNot, _, Some(255)) => {
+            drop_lhs(0)
+        }
+        (BitOr, _, Some(255)) => drop_lhs(255),
+        (Pow, _, Some(0)) => drop_lhs(1),
+        (Mul | BitAnd | Weight | Div | BitAndNot | BitLShift | BitRShift, Some(0), _) => {
+            drop_rhs(0)
+        }
+        (BitOr, Some(255), _) => drop_rhs(255),
+        (Pow, Some(1), _) => drop_rhs(1),
+        (BitAnd | BitOr, _, _) if pure_same => Some(lhs.clone()),
+        (Sub | BitXor | BitAndNot | Greater | Lt | Gt | Ne, _, _) if pure_same => {
+            Some(num(0, span))
+        }
+        (Eq | Le | Ge, _, _) if pure_same => Some(num(255, span)),
+        _ => None,
+    };
+
+    simplified.unwrap_or_else(|| {
+        Expr::new(
+            ExprKind::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+            span,
+        )
+    })
+}
+
+fn call(func: Func, args: Vec<Expr>, span: Span, known: &[Known]) -> Expr {
+    if !func.reads_pixel() {
+        if let Some(args) = args.iter().map(constant).collect::<Option<Vec<u8>>>() {
+            return num(fold_call(func, &args), span);
+        }
+    }
+
+    let pure_args = args.iter().all(|arg| is_pure(arg, known));
+    match func {
+        Func::Min | Func::Max | Func::Avg
+            if pure_args && args[1..].iter().all(|arg| same(arg, &args[0])) =>
+        {
+            return args.into_iter().next().unwrap();
+        }
+        Func::Clamp if constant(&args[1]) == Some(0) && constant(&args[2]) == Some(255) => {
+            return args.into_iter().next().unwrap();
+        }
+        Func::Lerp if is_pure(&args[1], known) && constant(&args[2]) == Some(0) => {
+            return args.into_iter().next().unwrap();
+        }
+        Func::Lerp if is_pure(&args[0], known) && constant(&args[2]) == Some(255) => {
+            return args.into_iter().nth(1).unwrap();
+        }
+        _ => {}
+    }
+
+    Expr::new(ExprKind::Call { func, args }, span)
+}
+
+/// Moves pure subexpressions used more than once into new bindings, smallest
+/// first so a bigger one can refer to the smaller ones inside it.
+fn share(roots: &mut [&mut Expr], bindings: &mut Vec<Binding>, known: &mut Vec<Known>) {
+    loop {
+        let mut nodes = Vec::new();
+        for root in roots.iter() {
+            collect(root, &mut nodes);
+        }
+
+        let shared = nodes
+            .iter()
+            .filter(|node| is_pure(node, known))
+            .filter(|node| nodes.iter().filter(|other| same(node, other)).count() > 1)
+            .min_by_key(|node| size(node));
+        let Some(shared) = shared.map(|node| (*node).clone()) else {
+            return;
+        };
+
+        let slot = bindings.len();
+        let name = fresh_name(bindings);
+        for root in roots.iter_mut() {
+            replace(root, &shared, slot, &name);
+        }
+        known.push(Known {
+            value: None,
+            pure: true,
+        });
+        bindings.push(Binding {
+            name,
+            span: shared.span,
+            value: shared,
+        });
+    }
+}
+
+/// Compound subexpressions of `expr`, the ones worth sharing.
+fn collect<'a>(expr: &'a Expr, out: &mut Vec<&'a Expr>) {
+    if !matches!(
+        expr.kind,
+        ExprKind::Num(_) | ExprKind::Var(_) | ExprKind::Local { .. } | ExprKind::Convolve { .. }
+    ) {
+        out.push(expr);
+    }
+    children(expr).for_each(|child| collect(child, out));
+}
+
+fn replace(expr: &mut Expr, target: &Expr, slot: usize, name: &str) {
+    if same(expr, target) {
+        expr.kind = ExprKind::Local {
+            slot,
+            name: name.to_string(),
+        };
+        return;
+    }
+    children_mut(expr).for_each(|child| replace(child, target, slot, name));
+}
+
+/// A binding name that can not be confused with one written by the user.
+fn fresh_name(bindings: &[Binding]) -> String {
+    (1..)
+        .map(|i| format!("_{}", i))
+        .find(|name| bindings.iter().all(|binding| binding.name != *name))
+        .unwrap()
+}
+
+/// Drops bindings that are no longer used, renumbering the slots of the others.
+fn prune(roots: &mut [&mut Expr], bindings: &mut Vec<Binding>) {
+    let mut used = vec![false; bindings.len()];
+    let mut pending: Vec<usize> = Vec::new();
+    for root in roots.iter() {
+        locals(root, &mut pending);
+    }
+    while let Some(slot) = pending.pop() {
+        if !used[slot] {
+            used[slot] = true;
+            locals(&bindings[slot].value, &mut pending);
+        }
+    }
+
+    let mut slots = Vec::with_capacity(bindings.len());
+    let mut next = 0;
+    for used in &used {
+        slots.push(next);
+        next += usize::from(*used);
+    }
+
+    let mut slot = 0;
+    bindings.retain(|_| {
+        slot += 1;
+        used[slot - 1]
+    });
+    for binding in bindings.iter_mut() {
+        renumber(&mut binding.value, &slots);
+    }
+    for root in roots.iter_mut() {
+        renumber(root, &slots);
+    }
+}
+
+fn locals(expr: &Expr, out: &mut Vec<usize>) {
+    if let ExprKind::Local { slot, .. } = expr.kind {
+        out.push(slot);
+    }
+    children(expr).for_each(|child| locals(child, out));
+}
+
+fn renumber(expr: &mut Expr, slots: &[usize]) {
+    if let ExprKind::Local { slot, .. } = &mut expr.kind {
+        *slot = slots[*slot];
+    }
+    children_mut(expr).for_each(|child| renumber(child, slots));
+}
+
+fn children(expr: &Expr) -> impl Iterator<Item = &Expr> {
+    let children: Vec<&Expr> = match &expr.kind {
+        ExprKind::Num(_)
+        | ExprKind::Var(_)
+        | ExprKind::Local { .. }
+        | ExprKind::Convolve { .. } => vec![],
+        ExprKind::Unary { operand, .. } | ExprKind::Swizzle { operand, .. } => vec![operand],
+        ExprKind::Group(inner) => vec![inner],
+        ExprKind::Offset { dx, dy, .. } => vec![dx, dy],
+        ExprKind::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+        ExprKind::Call { args: items, .. } | ExprKind::Channels(items) => items.iter().collect(),
+        ExprKind::Cond {
+            cond,
+            if_true,
+            if_false,
+        } => vec![cond, if_true, if_false],
+    };
+    children.into_iter()
+}
+
+fn children_mut(expr: &mut Expr) -> impl Iterator<Item = &mut Expr> {
+    let children: Vec<&mut Expr> = match &mut expr.kind {
+        ExprKind::Num(_)
+        | ExprKind::Var(_)
+        | ExprKind::Local { .. }
+        | ExprKind::Convolve { .. } => vec![],
+        ExprKind::Unary { operand, .. } | ExprKind::Swizzle { operand, .. } => vec![operand],
+        ExprKind::Group(inner) => vec![inner],
+        ExprKind::Offset { dx, dy, .. } => vec![dx, dy],
+        ExprKind::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+        ExprKind::Call { args: items, .. } | ExprKind::Channels(items) => {
+            items.iter_mut().collect()
+        }
+        ExprKind::Cond {
+            cond,
+            if_true,
+            if_false,
+        } => vec![cond, if_true, if_false],
+    };
+    children.into_iter()
+}
+
+fn num(n: u8, span: Span) -> Expr {
+    Expr::new(ExprKind::Num(n), span)
+}
+
+fn constant(expr: &Expr) -> Option<u8> {
+    match expr.kind {
+        ExprKind::Num(n) => Some(n),
+        _ => None,
+    }
+}
+
+/// Whether evaluating the expression draws no random numbers, so it can be
+/// skipped or evaluated once instead of several times. A local is as pure as its
+/// binding, skipping the only use of a binding skips evaluating it.
+fn is_pure(expr: &Expr, known: &[Known]) -> bool {
+    match expr.kind {
+        ExprKind::Var(Var::Noise | Var::Rand) => false,
+        ExprKind::Local { slot, .. } => known[slot].pure,
+        _ => children(expr).all(|child| is_pure(child, known)),
+    }
+}
+
+fn size(expr: &Expr) -> usize {
+    1 + children(expr).map(size).sum::<usize>()
+}
+
+/// Structural equality, ignoring where in the source the nodes come from.
+fn same(a: &Expr, b: &Expr) -> bool {
+    let shallow = match (&a.kind, &b.kind) {
+        (ExprKind::Num(x), ExprKind::Num(y)) => x == y,
+        (ExprKind::Var(x), ExprKind::Var(y)) => x == y,
+        (ExprKind::Local { slot: x, .. }, ExprKind::Local { slot: y, .. })
+        | (ExprKind::Convolve { slot: x, .. }, ExprKind::Convolve { slot: y, .. }) => x == y,
+        (ExprKind::Unary { op: x, .. }, ExprKind::Unary { op: y, .. }) => x == y,
+        (ExprKind::Swizzle { lanes: x, .. }, ExprKind::Swizzle { lanes: y, .. }) => x == y,
+        (ExprKind::Offset { var: x, .. }, ExprKind::Offset { var: y, .. }) => x == y,
+        (ExprKind::Binary { op: x, .. }, ExprKind::Binary { op: y, .. }) => x == y,
+        (ExprKind::Call { func: x, args: xs }, ExprKind::Call { func: y, args: ys }) => {
+            x == y && xs.len() == ys.len()
+        }
+        (ExprKind::Channels(xs), ExprKind::Channels(ys)) => xs.len() == ys.len(),
+        (ExprKind::Cond { .. }, ExprKind::Cond { .. })
+        | (ExprKind::Group(_), ExprKind::Group(_)) => true,
+        _ => false,
+    };
+    shallow && children(a).zip(children(b)).all(|(a, b)| same(a, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn optimized(input: &str) -> String {
+        optimize(parse(input).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(optimized("(128 & 255) + 0"), "128");
+        assert_eq!(optimized("c + (200 + 100)"), "c + 44");
+        assert_eq!(optimized("c * max(3, 7 - 5, 1)"), "c * 3");
+        assert_eq!(optimized("let k = 2 # 3; c ^ k"), "c ^ 8");
+        assert_eq!(optimized("if 4 ? 3 then c else b"), "c");
+        assert_eq!(optimized("c / (5 - 5)"), "c");
+    }
+
+    #[test]
+    fn test_identities() {
+        assert_eq!(optimized("(c + 0) * 1 | 0"), "c");
+        assert_eq!(optimized("~~(c & 255)"), "c");
+        assert_eq!(optimized("c.gbr.gbr.gbr"), "c");
+        assert_eq!(optimized("b - (c ^ c)"), "b");
+        assert_eq!(optimized("[h, h, h] << 8"), "h");
+        assert_eq!(optimized("lerp(c, b, 0)"), "c");
+    }
+
+    #[test]
+    fn test_keeps_random_numbers() {
+        assert_eq!(optimized("N * 0"), "N * 0");
+        assert_eq!(optimized("r - r"), "r - r");
+        assert_eq!(optimized("c + N * 0"), "c + N * 0");
+        assert_eq!(optimized("[N, N, N]"), "[N, N, N]");
+        assert_eq!(optimized("let k = N; k * 0 + N"), "let k = N; k * 0 + N");
+        assert_eq!(optimized("let k = r; (k & 0) ^ N"), "let k = r; k & 0 ^ N");
+        assert_eq!(
+            optimized("if c ? 100 then N else N"),
+            "if c ? 100 then N else N"
+        );
+    }
+
+    #[test]
+    fn test_shares_subexpressions() {
+        assert_eq!(
+            optimized("(c + 1) * (c + 1) + (c + 1) * (c + 1)"),
+            "let _1 = c + 1; let _2 = _1 * _1; _2 + _2"
+        );
+        assert_eq!(optimized("(N + 1) * (N + 1)"), "(N + 1) * (N + 1)");
+        assert_eq!(
+            optimized("let _1 = 3 + c; let k = c - 1; [_1 ^ (x * y), x * y, x * y, 9]"),
+            "let _1 = 3 + c; let _2 = x * y; [_1 ^ _2, _2, _2, 9]"
+        );
+    }
+
+    #[test]
+    fn test_precedence_is_kept() {
+        assert_eq!(optimized("(c + 1) * (b - (h - 0))"), "(c + 1) * (b - h)");
+        assert_eq!(optimized("-(c + 0 + b)"), "-(c + b)");
+        assert_eq!(optimized("(c ^ 1).g"), "(c ^ 1).g");
+        assert_eq!(optimized("c - (b + h)"), "c - (b + h)");
+        assert_eq!(optimized("(c - b) + h"), "c - b + h");
+    }
+}
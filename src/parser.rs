@@ -1,6 +1,10 @@
 #![allow(dead_code)]
 
-use std::collections::VecDeque;
+use crate::ast::{
+    BinOp, Binding, Convolution, Expr, ExprKind, Func, Overflow, Program, Span, UnOp, Var,
+};
+use crate::error::{ParseError, ParseErrorKind};
+use crate::validate;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub enum Token {
@@ -19,164 +23,904 @@ pub enum Token {
     BitRShift,
     Greater,
     Weight,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
     LeftParen,
     RightParen,
-    Char(char),
+    Var(Var),
+    Neg,
+    BitNot,
+    Abs,
+    /// A function call with the number of arguments it pops.
+    Call(Func, usize),
+    /// Pops a mask, a value and an alternative, selecting per component.
+    Select,
+    /// Reorders the components of a value by index.
+    Swizzle([u8; 3]),
+    /// Pops the x and y offsets and pushes the variable sampled there.
+    Sample(Var),
+    /// Pops one value per component and combines them into a color.
+    Channels,
+    /// Pops a color and the value for its alpha.
+    Alpha,
+    /// Pops the x and y coordinates and pushes the input read there.
+    Remap,
+    /// Pushes the value of a `let` binding.
+    Local(usize),
+    /// Pushes the image convolved with a `kernel` declaration.
+    Convolve(usize),
+    /// Pops the value of a `let` binding.
+    Store(usize),
 }
 
-pub(crate) fn shunting_yard(input: &str) -> Result<Vec<Token>, String> {
-    let mut output_queue: VecDeque<Token> = VecDeque::new();
-    let mut operator_stack: Vec<Token> = Vec::new();
-    let mut number_buffer: Option<u8> = None;
-    let mut current_position: usize = 0;
-
-    let push_number_buffer = |number_buffer: &mut Option<u8>,
-                              output_queue: &mut VecDeque<Token>,
-                              _position: usize|
-     -> Result<(), String> {
-        if let Some(number) = *number_buffer {
-            output_queue.push_back(Token::Num(number));
-            *number_buffer = None;
+impl Token {
+    /// Number of values the token pops from and pushes onto the evaluation stack,
+    /// `None` for parenthesis which never appear in a postfix stream.
+    pub fn arity(&self) -> Option<(usize, usize)> {
+        match self {
+            Token::Num(_) | Token::Var(_) | Token::Local(_) | Token::Convolve(_) => Some((0, 1)),
+            Token::Store(_) => Some((1, 0)),
+            Token::Neg | Token::BitNot | Token::Abs | Token::Swizzle(_) => Some((1, 1)),
+            Token::Call(_, argc) => Some((*argc, 1)),
+            Token::Sample(_) => Some((2, 1)),
+            Token::Select => Some((3, 1)),
+            Token::Channels => Some((3, 1)),
+            Token::Alpha | Token::Remap => Some((2, 1)),
+            Token::LeftParen | Token::RightParen => None,
+            _ => Some((2, 1)),
         }
-        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lexeme {
+    Num(u8),
+    Op(BinOp),
+    /// A prefix only operator, `-` is lexed as [`BinOp::Sub`] and resolved by
+    /// its position.
+    Unary(UnOp),
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Dot,
+    Var(Var),
+    /// A name that is not a variable, only valid when followed by `(`.
+    Ident,
+    If,
+    Then,
+    Else,
+    Let,
+    Kernel,
+    Assign,
+    Semicolon,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Spanned {
+    lexeme: Lexeme,
+    span: Span,
+}
+
+/// Which meaning `<` and `>` have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Syntax {
+    /// go-glitch compatible, `<` and `>` are bit shifts.
+    #[default]
+    Legacy,
+    /// `<` and `>` are comparisons, shifts are written `<<` and `>>`.
+    V2,
+}
+
+/// Parses an expression into its postfix token order.
+///
+/// Kept for compatibility with the go-glitch token format, the evaluator works
+/// on the tree returned by [`parse`].
+pub(crate) fn shunting_yard(input: &str) -> Result<Vec<Token>, ParseError> {
+    parse(input).map(|program| program.to_rpn())
+}
+
+/// Parses a program into a typed tree using the legacy syntax.
+pub fn parse(input: &str) -> Result<Program, ParseError> {
+    parse_with_syntax(input, Syntax::Legacy)
+}
+
+/// Parses a program, `kernel` declarations and `let` bindings followed by an
+/// expression, into a typed tree.
+pub fn parse_with_syntax(input: &str, syntax: Syntax) -> Result<Program, ParseError> {
+    parse_with_kernels(input, syntax, &[])
+}
+
+/// Parses a program that can also use `kernels` declared elsewhere, see
+/// [`parse_kernels`]. They come first in [`Program::kernels`].
+pub fn parse_with_kernels(
+    input: &str,
+    syntax: Syntax,
+    kernels: &[Convolution],
+) -> Result<Program, ParseError> {
+    let lexemes = lex(input, syntax)?;
+    if lexemes.is_empty() {
+        return Err(ParseError::new(
+            ParseErrorKind::EmptyExpression,
+            Span::new(0, input.len()),
+        ));
+    }
+
+    let mut parser = ExprParser {
+        input,
+        lexemes: &lexemes,
+        pos: 0,
+        locals: Vec::new(),
+        kernels: kernels.to_vec(),
     };
+    let program = parser.parse_program()?;
 
-    for c in input.chars() {
-        current_position += 1; // Update position for each character
-        match c {
+    validate::validate(&program)?;
+    Ok(program)
+}
+
+/// Parses a file made only of `kernel` declarations, shared by every expression.
+pub fn parse_kernels(input: &str, syntax: Syntax) -> Result<Vec<Convolution>, ParseError> {
+    let lexemes = lex(input, syntax)?;
+    let mut parser = ExprParser {
+        input,
+        lexemes: &lexemes,
+        pos: 0,
+        locals: Vec::new(),
+        kernels: Vec::new(),
+    };
+    while let Some(tok) = parser.next() {
+        match tok.lexeme {
+            Lexeme::Kernel => {
+                let kernel = parser.parse_kernel(tok)?;
+                parser.kernels.push(kernel);
+            }
+            _ => return Err(parser.unexpected(tok)),
+        }
+    }
+    Ok(parser.kernels)
+}
+
+fn lex(input: &str, syntax: Syntax) -> Result<Vec<Spanned>, ParseError> {
+    let mut lexemes = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let span = Span::new(offset, offset + c.len_utf8());
+        let lexeme = match c {
             '0'..='9' => {
-                let digit = c.to_digit(10).unwrap() as i64;
-                number_buffer = match number_buffer {
-                    Some(number) => {
-                        let new_number = number as i64 * 10i64 + digit;
-                        if new_number > 255 {
-                            return Err(format!(
-                                "Number exceeds 255 at position {}",
-                                current_position
-                            ));
-                        } else {
-                            Some(new_number as u8)
-                        }
+                let mut end = span.end;
+                while let Some((i, d)) = chars.next_if(|(_, d)| d.is_ascii_digit()) {
+                    end = i + d.len_utf8();
+                }
+
+                let span = Span::new(offset, end);
+                let number = input[offset..end]
+                    .bytes()
+                    .try_fold(0u8, |n, d| n.checked_mul(10)?.checked_add(d - b'0'))
+                    .ok_or(ParseError::new(ParseErrorKind::NumberOutOfRange, span))?;
+
+                lexemes.push(Spanned {
+                    lexeme: Lexeme::Num(number),
+                    span,
+                });
+                continue;
+            }
+            '(' => Lexeme::LeftParen,
+            ')' => Lexeme::RightParen,
+            '[' => Lexeme::LeftBracket,
+            ']' => Lexeme::RightBracket,
+            ',' => Lexeme::Comma,
+            '.' => Lexeme::Dot,
+            ';' => Lexeme::Semicolon,
+            '~' => Lexeme::Unary(UnOp::Not),
+            _ if c.is_whitespace() => continue,
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = span.end;
+                while let Some((i, d)) =
+                    chars.next_if(|(_, d)| d.is_ascii_alphanumeric() || *d == '_')
+                {
+                    end = i + d.len_utf8();
+                }
+
+                let span = Span::new(offset, end);
+                let lexeme = match &input[offset..end] {
+                    "abs" => Lexeme::Unary(UnOp::Abs),
+                    "if" => Lexeme::If,
+                    "then" => Lexeme::Then,
+                    "else" => Lexeme::Else,
+                    "let" => Lexeme::Let,
+                    "kernel" => Lexeme::Kernel,
+                    name => match Var::from_name(name) {
+                        Some(var) => Lexeme::Var(var),
+                        None => Lexeme::Ident,
+                    },
+                };
+
+                lexemes.push(Spanned { lexeme, span });
+                continue;
+            }
+            '<' | '>' | '=' | '!' => {
+                let next = chars.peek().map(|(_, d)| *d);
+                let (op, len) = match (c, next) {
+                    ('<', Some('<')) => (BinOp::BitLShift, 2),
+                    ('>', Some('>')) => (BinOp::BitRShift, 2),
+                    ('<', Some('=')) => (BinOp::Le, 2),
+                    ('>', Some('=')) => (BinOp::Ge, 2),
+                    ('=', Some('=')) => (BinOp::Eq, 2),
+                    ('!', Some('=')) => (BinOp::Ne, 2),
+                    ('<', _) if syntax == Syntax::Legacy => (BinOp::BitLShift, 1),
+                    ('>', _) if syntax == Syntax::Legacy => (BinOp::BitRShift, 1),
+                    ('<', _) => (BinOp::Lt, 1),
+                    ('>', _) => (BinOp::Gt, 1),
+                    ('=', _) => {
+                        lexemes.push(Spanned {
+                            lexeme: Lexeme::Assign,
+                            span,
+                        });
+                        continue;
                     }
-                    None => Some(digit as u8),
+                    _ => return Err(ParseError::new(ParseErrorKind::InvalidCharacter(c), span)),
                 };
+                if len == 2 {
+                    chars.next();
+                }
+
+                lexemes.push(Spanned {
+                    lexeme: Lexeme::Op(op),
+                    span: Span::new(offset, offset + len),
+                });
+                continue;
             }
-            c if char_to_token(c).is_some() => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                if let Some(token) = char_to_token(c) {
-                    handle_operator(&mut operator_stack, &mut output_queue, token);
+            _ => match char_to_op(c) {
+                Some(op) => Lexeme::Op(op),
+                None => return Err(ParseError::new(ParseErrorKind::InvalidCharacter(c), span)),
+            },
+        };
+
+        lexemes.push(Spanned { lexeme, span });
+    }
+
+    Ok(lexemes)
+}
+
+/// Precedence climbing parser over the lexed input.
+struct ExprParser<'a> {
+    input: &'a str,
+    lexemes: &'a [Spanned],
+    pos: usize,
+    /// Names of the bindings so far, indexed by slot. Later bindings shadow
+    /// earlier ones with the same name.
+    locals: Vec<String>,
+    /// Kernels declared so far, their names can not be reused.
+    kernels: Vec<Convolution>,
+}
+
+impl<'a> ExprParser<'a> {
+    fn peek(&self) -> Option<&'a Spanned> {
+        self.lexemes.get(self.pos)
+    }
+
+    fn previous(&self) -> Option<&'a Spanned> {
+        self.pos.checked_sub(1).and_then(|i| self.lexemes.get(i))
+    }
+
+    fn next(&mut self) -> Option<&'a Spanned> {
+        let tok = self.lexemes.get(self.pos);
+        self.pos += 1;
+        tok
+    }
+
+    fn parse_program(&mut self) -> Result<Program, ParseError> {
+        let mut bindings = Vec::new();
+        while let Some(tok) = self.peek() {
+            match tok.lexeme {
+                Lexeme::Let => {
+                    self.pos += 1;
+                    bindings.push(self.parse_let(tok)?);
+                }
+                Lexeme::Kernel => {
+                    self.pos += 1;
+                    let kernel = self.parse_kernel(tok)?;
+                    self.kernels.push(kernel);
+                }
+                _ => break,
+            }
+        }
+
+        let body = self.parse_expr(0)?;
+        let remap = match self.peek() {
+            Some(tok) if tok.lexeme == Lexeme::Comma => {
+                self.pos += 1;
+                Some(self.parse_expr(0)?)
+            }
+            _ => None,
+        };
+        if self.peek().map(|tok| tok.lexeme) == Some(Lexeme::Semicolon) {
+            self.pos += 1;
+        }
+
+        match self.peek() {
+            None => Ok(Program {
+                kernels: std::mem::take(&mut self.kernels),
+                ..match remap {
+                    Some(y) => Program::with_remap(bindings, body, y),
+                    None => Program::new(bindings, body),
                 }
+            }),
+            Some(tok) if tok.lexeme == Lexeme::RightParen => {
+                Err(ParseError::new(ParseErrorKind::UnbalancedParen, tok.span))
+            }
+            Some(tok) if tok.lexeme == Lexeme::RightBracket => {
+                Err(ParseError::new(ParseErrorKind::UnbalancedBracket, tok.span))
+            }
+            Some(tok)
+                if matches!(
+                    tok.lexeme,
+                    Lexeme::Var(_)
+                        | Lexeme::Num(_)
+                        | Lexeme::LeftParen
+                        | Lexeme::LeftBracket
+                        | Lexeme::Ident
+                ) =>
+            {
+                Err(ParseError::new(ParseErrorKind::MissingOperator, tok.span))
             }
-            '(' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                operator_stack.push(Token::LeftParen);
+            Some(tok) => Err(self.unexpected(tok)),
+        }
+    }
+
+    /// Parses `name = value;` with `let` already consumed.
+    fn parse_let(&mut self, keyword: &Spanned) -> Result<Binding, ParseError> {
+        let name = self.parse_name()?;
+        self.expect(Lexeme::Assign, "=")?;
+        let value = self.parse_expr(0)?;
+        let end = self.expect(Lexeme::Semicolon, ";")?;
+
+        self.locals.push(name.clone());
+        Ok(Binding {
+            name,
+            value,
+            span: keyword.span.to(end.span),
+        })
+    }
+
+    /// Parses `name = [[w, ...], ...] / divisor clamp;` with `kernel` already
+    /// consumed. The divisor defaults to 1 and the overflow to wrapping.
+    fn parse_kernel(&mut self, keyword: &Spanned) -> Result<Convolution, ParseError> {
+        let name = self.parse_name()?;
+        if self.locals.contains(&name) {
+            let span = self.previous().unwrap().span;
+            return Err(ParseError::new(ParseErrorKind::DuplicateName(name), span));
+        }
+        self.expect(Lexeme::Assign, "=")?;
+
+        let open = self.expect(Lexeme::LeftBracket, "[")?;
+        let mut weights = Vec::new();
+        loop {
+            let row = self.expect(Lexeme::LeftBracket, "[")?;
+            weights.push(self.parse_weights(row)?);
+            match self.next() {
+                Some(tok) if tok.lexeme == Lexeme::Comma => continue,
+                Some(tok) if tok.lexeme == Lexeme::RightBracket => break,
+                Some(tok) => return Err(self.unexpected(tok)),
+                None => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnbalancedBracket,
+                        open.span,
+                    ))
+                }
             }
-            ')' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                while let Some(op) = operator_stack.pop() {
-                    if matches!(op, Token::LeftParen) {
-                        break;
+        }
+        let matrix = open.span.to(self.previous().unwrap().span);
+        let invalid = |what| Err(ParseError::new(ParseErrorKind::InvalidKernel(what), matrix));
+        let (rows, cols) = (weights.len(), weights[0].len());
+        if weights.iter().any(|row| row.len() != cols) {
+            return invalid("rows have different lengths");
+        }
+        if rows % 2 == 0 || cols % 2 == 0 {
+            return invalid("sizes have to be odd to have a center");
+        }
+
+        let divisor = match self.peek().map(|tok| tok.lexeme) {
+            Some(Lexeme::Op(BinOp::Div)) => {
+                self.pos += 1;
+                match self.next() {
+                    Some(Spanned {
+                        lexeme: Lexeme::Num(0),
+                        span,
+                    }) => {
+                        let kind = ParseErrorKind::InvalidKernel("the divisor can not be 0");
+                        return Err(ParseError::new(kind, *span));
                     }
-                    output_queue.push_back(op);
+                    Some(Spanned {
+                        lexeme: Lexeme::Num(n),
+                        ..
+                    }) => *n,
+                    Some(tok) => return Err(self.unexpected(tok)),
+                    None => return Err(self.expected("divisor")),
                 }
             }
-            _ if c.is_whitespace() => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
+            _ => 1,
+        };
+
+        let overflow = match self.peek() {
+            Some(tok) if tok.lexeme == Lexeme::Ident => {
+                self.pos += 1;
+                match &self.input[tok.span.start..tok.span.end] {
+                    "wrap" => Overflow::Wrap,
+                    "clamp" => Overflow::Clamp,
+                    _ => return Err(self.unexpected(tok)),
+                }
             }
-            _ if valid_tok(c) => {
-                output_queue.push_back(Token::Char(c));
+            _ => Overflow::Wrap,
+        };
+        let end = self.expect(Lexeme::Semicolon, ";")?;
+
+        Ok(Convolution {
+            name,
+            weights,
+            divisor,
+            overflow,
+            span: keyword.span.to(end.span),
+        })
+    }
+
+    /// Parses a row of weights like `-1, 0, 1]` with `[` already consumed.
+    fn parse_weights(&mut self, open: &Spanned) -> Result<Vec<i16>, ParseError> {
+        let mut row = Vec::new();
+        loop {
+            let negative = match self.peek().map(|tok| tok.lexeme) {
+                Some(Lexeme::Op(BinOp::Sub)) => {
+                    self.pos += 1;
+                    true
+                }
+                _ => false,
+            };
+            let weight = match self.next() {
+                Some(Spanned {
+                    lexeme: Lexeme::Num(n),
+                    ..
+                }) => i16::from(*n),
+                Some(tok) => return Err(self.unexpected(tok)),
+                None => return Err(self.expected("weight")),
+            };
+            row.push(if negative { -weight } else { weight });
+
+            match self.next() {
+                Some(tok) if tok.lexeme == Lexeme::Comma => continue,
+                Some(tok) if tok.lexeme == Lexeme::RightBracket => return Ok(row),
+                Some(tok) => return Err(self.unexpected(tok)),
+                None => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnbalancedBracket,
+                        open.span,
+                    ))
+                }
             }
-            _ => {
-                return Err(format!(
-                    "Invalid character '{}' at position {}",
-                    c, current_position
+        }
+    }
+
+    /// Parses the name of a `let` or a `kernel`, which can not be a built-in name
+    /// or the name of a kernel.
+    fn parse_name(&mut self) -> Result<String, ParseError> {
+        let name_tok = match self.next() {
+            Some(tok) => tok,
+            None => return Err(self.expected("name")),
+        };
+        let name = &self.input[name_tok.span.start..name_tok.span.end];
+        match name_tok.lexeme {
+            Lexeme::Ident if self.kernels.iter().any(|kernel| kernel.name == name) => {
+                Err(ParseError::new(
+                    ParseErrorKind::DuplicateName(name.to_string()),
+                    name_tok.span,
                 ))
             }
+            Lexeme::Ident if Func::from_name(name).is_none() => Ok(name.to_string()),
+            Lexeme::Ident
+            | Lexeme::Var(_)
+            | Lexeme::Unary(_)
+            | Lexeme::If
+            | Lexeme::Then
+            | Lexeme::Else
+            | Lexeme::Let
+            | Lexeme::Kernel => Err(ParseError::new(
+                ParseErrorKind::ReservedName(name.to_string()),
+                name_tok.span,
+            )),
+            _ => Err(ParseError::new(
+                ParseErrorKind::Expected("name"),
+                name_tok.span,
+            )),
         }
     }
 
-    push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
+    fn parse_expr(&mut self, min_prec: u8) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(Spanned {
+            lexeme: Lexeme::Op(op),
+            ..
+        }) = self.peek()
+        {
+            let op = *op;
+            if op.precedence() < min_prec {
+                break;
+            }
+            self.pos += 1;
 
-    while let Some(op) = operator_stack.pop() {
-        if matches!(op, Token::LeftParen) {
-            return Err("Mismatched parenthesis detected".to_string());
+            let rhs = self.parse_expr(op.precedence() + 1)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr::new(
+                ExprKind::Binary {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            );
         }
-        output_queue.push_back(op);
+
+        Ok(lhs)
     }
 
-    Ok(output_queue.into())
-}
+    /// Prefix operators bind tighter than any binary operator, `-c # 2` is
+    /// `(-c) # 2`.
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.peek().map(|tok| tok.lexeme) {
+            Some(Lexeme::Op(BinOp::Sub)) => UnOp::Neg,
+            Some(Lexeme::Unary(op)) => op,
+            _ => return self.parse_postfix(),
+        };
+        let op_span = self.next().map(|tok| tok.span).unwrap_or_default();
+
+        let operand = self.parse_unary()?;
+        let span = op_span.to(operand.span);
+        Ok(Expr::new(
+            ExprKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+            span,
+        ))
+    }
+
+    /// Postfix operators bind tighter than prefix ones, `-c.g` is `-(c.g)`.
+    fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_primary()?;
+        if let ExprKind::Var(var) = expr.kind {
+            if let Some(open) = self.peek().filter(|tok| tok.lexeme == Lexeme::LeftBracket) {
+                self.pos += 1;
+                expr = self.parse_offset(var, expr.span, open)?;
+            }
+        }
+
+        while let Some(dot) = self.peek().filter(|tok| tok.lexeme == Lexeme::Dot) {
+            self.pos += 1;
+            let Some(name) = self.next() else {
+                return Err(self.expected("channels"));
+            };
+
+            let text = &self.input[name.span.start..name.span.end];
+            let lanes = swizzle_lanes(text).ok_or(ParseError::new(
+                ParseErrorKind::InvalidSwizzle(text.to_string()),
+                dot.span.to(name.span),
+            ))?;
+
+            let span = expr.span.to(name.span);
+            expr = Expr::new(
+                ExprKind::Swizzle {
+                    operand: Box::new(expr),
+                    lanes,
+                },
+                span,
+            );
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let previous = self.previous();
+        let Some(tok) = self.next() else {
+            return Err(match previous {
+                Some(prev) if prev.lexeme == Lexeme::LeftParen => {
+                    ParseError::new(ParseErrorKind::UnbalancedParen, prev.span)
+                }
+                Some(prev) if prev.lexeme == Lexeme::LeftBracket => {
+                    ParseError::new(ParseErrorKind::UnbalancedBracket, prev.span)
+                }
+                Some(prev) if prev.lexeme == Lexeme::Semicolon => ParseError::new(
+                    ParseErrorKind::EmptyExpression,
+                    Span::new(self.input.len(), self.input.len()),
+                ),
+                Some(prev) => ParseError::new(ParseErrorKind::DanglingOperator, prev.span),
+                None => ParseError::new(
+                    ParseErrorKind::EmptyExpression,
+                    Span::new(self.input.len(), self.input.len()),
+                ),
+            });
+        };
+
+        match tok.lexeme {
+            Lexeme::Num(n) => Ok(Expr::new(ExprKind::Num(n), tok.span)),
+            Lexeme::Var(var) => match (Func::with_radius(var), self.peek()) {
+                (Some(func), Some(open)) if open.lexeme == Lexeme::LeftParen => {
+                    self.pos += 1;
+                    self.parse_args(func, tok.span, open)
+                }
+                _ => Ok(Expr::new(ExprKind::Var(var), tok.span)),
+            },
+            Lexeme::LeftParen => {
+                let inner = self.parse_expr(0)?;
+                match self.next() {
+                    Some(Spanned {
+                        lexeme: Lexeme::RightParen,
+                        span,
+                    }) => Ok(Expr::new(
+                        ExprKind::Group(Box::new(inner)),
+                        tok.span.to(*span),
+                    )),
+                    Some(other) => {
+                        Err(ParseError::new(ParseErrorKind::MissingOperator, other.span))
+                    }
+                    None => Err(ParseError::new(ParseErrorKind::UnbalancedParen, tok.span)),
+                }
+            }
+            Lexeme::LeftBracket => self.parse_channels(tok),
+            Lexeme::Ident => self.parse_call(tok),
+            Lexeme::If => self.parse_if(tok),
+            Lexeme::Then
+            | Lexeme::Else
+            | Lexeme::Let
+            | Lexeme::Kernel
+            | Lexeme::Assign
+            | Lexeme::Semicolon
+            | Lexeme::Dot => Err(self.unexpected(tok)),
+            Lexeme::Comma => Err(self.unexpected(tok)),
+            Lexeme::RightParen | Lexeme::RightBracket => Err(match previous {
+                Some(prev)
+                    if matches!(
+                        prev.lexeme,
+                        Lexeme::LeftParen | Lexeme::LeftBracket | Lexeme::Comma
+                    ) =>
+                {
+                    ParseError::new(ParseErrorKind::EmptyExpression, prev.span.to(tok.span))
+                }
+                Some(prev) if matches!(prev.lexeme, Lexeme::Op(_) | Lexeme::Unary(_)) => {
+                    ParseError::new(ParseErrorKind::DanglingOperator, prev.span)
+                }
+                _ if tok.lexeme == Lexeme::RightBracket => {
+                    ParseError::new(ParseErrorKind::UnbalancedBracket, tok.span)
+                }
+                _ => ParseError::new(ParseErrorKind::UnbalancedParen, tok.span),
+            }),
+            Lexeme::Op(_) | Lexeme::Unary(_) => {
+                Err(ParseError::new(ParseErrorKind::DanglingOperator, tok.span))
+            }
+        }
+    }
 
-fn handle_operator(operator_stack: &mut Vec<Token>, output_queue: &mut VecDeque<Token>, op: Token) {
-    while let Some(top_op) = operator_stack.last() {
-        if is_higher_precedence(&op, top_op) {
-            break;
+    /// Parses `[r, g, b]` or `[r, g, b, a]` with `[` already consumed. Whether
+    /// alpha is allowed depends on the position and is checked by validation.
+    fn parse_channels(&mut self, open: &Spanned) -> Result<Expr, ParseError> {
+        let (items, close) = self.parse_list(open, Lexeme::RightBracket)?;
+        let span = open.span.to(close);
+        if !(3..=4).contains(&items.len()) {
+            return Err(ParseError::new(
+                ParseErrorKind::ChannelCount(items.len()),
+                span,
+            ));
         }
-        output_queue.push_back(operator_stack.pop().unwrap());
+
+        Ok(Expr::new(ExprKind::Channels(items), span))
     }
-    operator_stack.push(op);
-}
 
-fn is_higher_precedence(new_op: &Token, top_op: &Token) -> bool {
-    let (new_prec, _) = operator_precedence(new_op);
-    let (_, top_prec) = operator_precedence(top_op);
-    new_prec > top_prec
-}
+    /// Parses the `[dx, dy]` following a variable, with `[` already consumed.
+    fn parse_offset(&mut self, var: Var, name: Span, open: &Spanned) -> Result<Expr, ParseError> {
+        if !var.is_spatial() {
+            let text = &self.input[name.start..name.end];
+            return Err(ParseError::new(
+                ParseErrorKind::NotSpatial(text.to_string()),
+                name.to(open.span),
+            ));
+        }
+
+        let (mut items, close) = self.parse_list(open, Lexeme::RightBracket)?;
+        if items.len() != 2 {
+            return Err(ParseError::new(
+                ParseErrorKind::OffsetCount(items.len()),
+                open.span.to(close),
+            ));
+        }
+
+        let dy = items.pop().unwrap();
+        let dx = items.pop().unwrap();
+        Ok(Expr::new(
+            ExprKind::Offset {
+                var,
+                dx: Box::new(dx),
+                dy: Box::new(dy),
+            },
+            name.to(close),
+        ))
+    }
+
+    /// Parses comma separated expressions up to `close`, with the opening token
+    /// already consumed. Returns the expressions and the span of `close`.
+    fn parse_list(
+        &mut self,
+        open: &Spanned,
+        close: Lexeme,
+    ) -> Result<(Vec<Expr>, Span), ParseError> {
+        let mut items = Vec::new();
+        if let Some(tok) = self.peek().filter(|tok| tok.lexeme == close) {
+            self.pos += 1;
+            return Ok((items, tok.span));
+        }
+
+        loop {
+            items.push(self.parse_expr(0)?);
+            match self.next() {
+                Some(tok) if tok.lexeme == Lexeme::Comma => continue,
+                Some(tok) if tok.lexeme == close => return Ok((items, tok.span)),
+                Some(tok) => {
+                    return Err(ParseError::new(ParseErrorKind::MissingOperator, tok.span))
+                }
+                None if close == Lexeme::RightBracket => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnbalancedBracket,
+                        open.span,
+                    ))
+                }
+                None => return Err(ParseError::new(ParseErrorKind::UnbalancedParen, open.span)),
+            }
+        }
+    }
+
+    /// Parses `if cond then a else b` with `if` already consumed. The branches
+    /// extend as far to the right as possible, like the body of a lambda.
+    fn parse_if(&mut self, keyword: &Spanned) -> Result<Expr, ParseError> {
+        let cond = self.parse_expr(0)?;
+        self.expect(Lexeme::Then, "then")?;
+        let if_true = self.parse_expr(0)?;
+        self.expect(Lexeme::Else, "else")?;
+        let if_false = self.parse_expr(0)?;
+
+        let span = keyword.span.to(if_false.span);
+        Ok(Expr::new(
+            ExprKind::Cond {
+                cond: Box::new(cond),
+                if_true: Box::new(if_true),
+                if_false: Box::new(if_false),
+            },
+            span,
+        ))
+    }
+
+    fn expect(&mut self, lexeme: Lexeme, what: &'static str) -> Result<&'a Spanned, ParseError> {
+        match self.next() {
+            Some(tok) if tok.lexeme == lexeme => Ok(tok),
+            Some(tok) => Err(ParseError::new(ParseErrorKind::Expected(what), tok.span)),
+            None => Err(self.expected(what)),
+        }
+    }
+
+    /// Error for input ending where `what` had to follow.
+    fn expected(&self, what: &'static str) -> ParseError {
+        let end = Span::new(self.input.len(), self.input.len());
+        ParseError::new(ParseErrorKind::Expected(what), end)
+    }
+
+    fn unexpected(&self, tok: &Spanned) -> ParseError {
+        let text = &self.input[tok.span.start..tok.span.end];
+        ParseError::new(ParseErrorKind::UnexpectedToken(text.to_string()), tok.span)
+    }
+
+    /// Parses `name(arg, ...)`, or a reference to a binding, with `name` already
+    /// consumed.
+    fn parse_call(&mut self, name: &Spanned) -> Result<Expr, ParseError> {
+        let ident = &self.input[name.span.start..name.span.end];
+        let open = match self.peek() {
+            Some(tok) if tok.lexeme == Lexeme::LeftParen => *tok,
+            _ if self.kernels.iter().any(|kernel| kernel.name == ident) => {
+                let slot = self
+                    .kernels
+                    .iter()
+                    .position(|kernel| kernel.name == ident)
+                    .unwrap();
+                return Ok(Expr::new(
+                    ExprKind::Convolve {
+                        slot,
+                        name: ident.to_string(),
+                    },
+                    name.span,
+                ));
+            }
+            _ if self.locals.iter().any(|local| local == ident) => {
+                let slot = self
+                    .locals
+                    .iter()
+                    .rposition(|local| local == ident)
+                    .unwrap();
+                return Ok(Expr::new(
+                    ExprKind::Local {
+                        slot,
+                        name: ident.to_string(),
+                    },
+                    name.span,
+                ));
+            }
+            _ => {
+                return Err(ParseError::new(
+                    ParseErrorKind::UnknownVariable(ident.to_string()),
+                    name.span,
+                ))
+            }
+        };
+        let func = Func::from_name(ident).ok_or(ParseError::new(
+            ParseErrorKind::UnknownFunction(ident.to_string()),
+            name.span,
+        ))?;
+        self.pos += 1;
+        self.parse_args(func, name.span, &open)
+    }
 
-fn operator_precedence(op: &Token) -> (i32, i32) {
-    match op {
-        Token::Add | Token::Sub | Token::BitOr | Token::BitXor => (4, 4),
-        Token::Mul
-        | Token::Div
-        | Token::Mod
-        | Token::BitAnd
-        | Token::BitAndNot
-        | Token::BitLShift
-        | Token::BitRShift
-        | Token::Pow => (5, 5),
-        Token::Greater | Token::Weight => (6, 6),
-        _ => (-1, -1),
+    /// Parses the arguments of a call to `func` with `(` already consumed.
+    fn parse_args(&mut self, func: Func, name: Span, open: &Spanned) -> Result<Expr, ParseError> {
+        let (args, close) = self.parse_list(open, Lexeme::RightParen)?;
+        let span = name.to(close);
+        let (min, max) = func.arity();
+        if args.len() < min || max.is_some_and(|max| args.len() > max) {
+            return Err(ParseError::new(
+                ParseErrorKind::ArgumentCount {
+                    func,
+                    found: args.len(),
+                },
+                span,
+            ));
+        }
+
+        Ok(Expr::new(ExprKind::Call { func, args }, span))
     }
 }
 
-fn valid_tok(tok: char) -> bool {
-    matches!(
-        tok,
-        'c' | 's'
-            | 'Y'
-            | 'r'
-            | 'x'
-            | 'y'
-            | 'N'
-            | 'R'
-            | 'G'
-            | 'B'
-            | 'e'
-            | 'b'
-            | 'H'
-            | 'L'
-            | 'h'
-            | 'v'
-            | 'd'
-    )
+/// Component indices of a swizzle like `g` or `bgr`, a single letter is broadcast
+/// to all three components.
+fn swizzle_lanes(text: &str) -> Option<[u8; 3]> {
+    let lane = |c: u8| match c {
+        b'r' => Some(0),
+        b'g' => Some(1),
+        b'b' => Some(2),
+        _ => None,
+    };
+
+    match text.as_bytes() {
+        [c] => lane(*c).map(|l| [l; 3]),
+        [r, g, b] => Some([lane(*r)?, lane(*g)?, lane(*b)?]),
+        _ => None,
+    }
 }
 
-fn char_to_token(c: char) -> Option<Token> {
+fn char_to_op(c: char) -> Option<BinOp> {
     match c {
-        '+' => Some(Token::Add),
-        '-' => Some(Token::Sub),
-        '*' => Some(Token::Mul),
-        '/' => Some(Token::Div),
-        '%' => Some(Token::Mod),
-        '#' => Some(Token::Pow),
-        '&' => Some(Token::BitAnd),
-        '|' => Some(Token::BitOr),
-        ':' => Some(Token::BitAndNot),
-        '^' => Some(Token::BitXor),
-        '<' => Some(Token::BitLShift),
-        '>' => Some(Token::BitRShift),
-        '?' => Some(Token::Greater),
-        '@' => Some(Token::Weight),
+        '+' => Some(BinOp::Add),
+        '-' => Some(BinOp::Sub),
+        '*' => Some(BinOp::Mul),
+        '/' => Some(BinOp::Div),
+        '%' => Some(BinOp::Mod),
+        '#' => Some(BinOp::Pow),
+        '&' => Some(BinOp::BitAnd),
+        '|' => Some(BinOp::BitOr),
+        ':' => Some(BinOp::BitAndNot),
+        '^' => Some(BinOp::BitXor),
+        '?' => Some(BinOp::Greater),
+        '@' => Some(BinOp::Weight),
         _ => None,
     }
 }
@@ -239,7 +983,11 @@ mod tests {
     #[test]
     fn test_valid_characters() {
         let input = "c+Y";
-        let expected = Ok(vec![Token::Char('c'), Token::Char('Y'), Token::Add]);
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Var(Var::Lum),
+            Token::Add,
+        ]);
         assert_eq!(shunting_yard(input), expected);
     }
 
@@ -259,4 +1007,586 @@ mod tests {
         ]);
         assert_eq!(shunting_yard(input), expected);
     }
+
+    #[test]
+    fn test_parse_tree() {
+        let expr = parse("c + 5 * b").unwrap().body;
+        assert_eq!(expr.span, Span::new(0, 9));
+        match expr.kind {
+            ExprKind::Binary { op, lhs, rhs } => {
+                assert_eq!(op, BinOp::Add);
+                assert_eq!(lhs.kind, ExprKind::Var(Var::Color));
+                assert!(matches!(rhs.kind, ExprKind::Binary { op: BinOp::Mul, .. }));
+                assert_eq!(rhs.span, Span::new(4, 9));
+            }
+            kind => panic!("expected binary expression, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_left_associative() {
+        let expected = Ok(vec![
+            Token::Num(9),
+            Token::Num(3),
+            Token::Sub,
+            Token::Num(2),
+            Token::Sub,
+        ]);
+        assert_eq!(shunting_yard("9 - 3 - 2"), expected);
+    }
+
+    #[test]
+    fn test_group_span() {
+        let expr = parse(" (c & 12)").unwrap().body;
+        assert!(matches!(expr.kind, ExprKind::Group(_)));
+        assert_eq!(expr.span, Span::new(1, 9));
+        assert_eq!(expr.to_string(), "(c & 12)");
+    }
+
+    #[test]
+    fn test_dangling_operator() {
+        assert!(parse("c +").is_err());
+        assert!(parse("c c").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("c)").is_err());
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let kind = |input: &str| parse(input).unwrap_err().kind;
+        assert_eq!(
+            kind("c + q"),
+            ParseErrorKind::UnknownVariable("q".to_string())
+        );
+        assert_eq!(kind("3$5"), ParseErrorKind::InvalidCharacter('$'));
+        assert_eq!(kind("c + 1000"), ParseErrorKind::NumberOutOfRange);
+        assert_eq!(kind("(c + 1"), ParseErrorKind::UnbalancedParen);
+        assert_eq!(kind("c + 1)"), ParseErrorKind::UnbalancedParen);
+        assert_eq!(kind("c +"), ParseErrorKind::DanglingOperator);
+        assert_eq!(kind("c c"), ParseErrorKind::MissingOperator);
+        assert_eq!(kind("  "), ParseErrorKind::EmptyExpression);
+        assert_eq!(kind("c + ()"), ParseErrorKind::EmptyExpression);
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let span = |input: &str| parse(input).unwrap_err().span;
+        assert_eq!(span("c + 1000"), Span::new(4, 8));
+        assert_eq!(span("c * (b + 1"), Span::new(4, 5));
+        assert_eq!(span("c & b +"), Span::new(6, 7));
+    }
+
+    #[test]
+    fn test_unary_operators() {
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Neg,
+            Token::Num(2),
+            Token::Pow,
+            Token::Var(Var::Blur),
+            Token::BitNot,
+            Token::Add,
+        ]);
+        assert_eq!(shunting_yard("-c # 2 + ~b"), expected);
+
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Var(Var::Blur),
+            Token::Sub,
+            Token::Abs,
+            Token::Neg,
+        ]);
+        assert_eq!(shunting_yard("-abs (c - b)"), expected);
+    }
+
+    #[test]
+    fn test_binary_minus_after_operand() {
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Num(3),
+            Token::Neg,
+            Token::Sub,
+        ]);
+        assert_eq!(shunting_yard("c - -3"), expected);
+        assert_eq!(
+            parse("c + ~").unwrap_err().kind,
+            ParseErrorKind::DanglingOperator
+        );
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Num(2),
+            Token::Mul,
+            Token::Num(30),
+            Token::Num(200),
+            Token::Call(Func::Clamp, 3),
+        ]);
+        assert_eq!(shunting_yard("clamp(c*2, 30, 200)"), expected);
+
+        let expr = parse("max(c, b, h) + 1").unwrap().body;
+        assert_eq!(expr.to_string(), "max(c, b, h) + 1");
+        match expr.kind {
+            ExprKind::Binary { lhs, .. } => assert_eq!(lhs.span, Span::new(0, 12)),
+            kind => panic!("expected binary expression, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_function_errors() {
+        let kind = |input: &str| parse(input).unwrap_err().kind;
+        assert_eq!(
+            kind("foo(c)"),
+            ParseErrorKind::UnknownFunction("foo".to_string())
+        );
+        assert_eq!(
+            kind("max + c"),
+            ParseErrorKind::UnknownVariable("max".to_string())
+        );
+        assert_eq!(
+            kind("lerp(c, h)"),
+            ParseErrorKind::ArgumentCount {
+                func: Func::Lerp,
+                found: 2
+            }
+        );
+        assert_eq!(kind("min(c, b"), ParseErrorKind::UnbalancedParen);
+        assert_eq!(kind("min(c, )"), ParseErrorKind::EmptyExpression);
+        assert_eq!(
+            kind("c, b, h"),
+            ParseErrorKind::UnexpectedToken(",".to_string())
+        );
+    }
+
+    #[test]
+    fn test_conditional() {
+        let expr = parse("if Y ? 128 then c else N + 1").unwrap().body;
+        assert_eq!(expr.span, Span::new(0, 28));
+        match &expr.kind {
+            ExprKind::Cond { cond, if_false, .. } => {
+                assert!(matches!(
+                    cond.kind,
+                    ExprKind::Binary {
+                        op: BinOp::Greater,
+                        ..
+                    }
+                ));
+                assert!(matches!(
+                    if_false.kind,
+                    ExprKind::Binary { op: BinOp::Add, .. }
+                ));
+            }
+            kind => panic!("expected conditional, got {:?}", kind),
+        }
+        assert_eq!(expr.to_string(), "if Y ? 128 then c else N + 1");
+
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Var(Var::Saved),
+            Token::Var(Var::Blur),
+            Token::Select,
+            Token::Num(1),
+            Token::BitAnd,
+        ]);
+        assert_eq!(shunting_yard("(if c then s else b) & 1"), expected);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let err = |input: &str| parse(input).unwrap_err();
+        assert_eq!(err("if c else b").kind, ParseErrorKind::Expected("then"));
+        assert_eq!(err("if c else b").span, Span::new(5, 9));
+        assert_eq!(err("if c then b").kind, ParseErrorKind::Expected("else"));
+        assert_eq!(
+            err("c else b").kind,
+            ParseErrorKind::UnexpectedToken("else".to_string())
+        );
+    }
+
+    #[test]
+    fn test_legacy_shifts() {
+        let expected = Ok(vec![
+            Token::Var(Var::Color),
+            Token::Num(1),
+            Token::BitRShift,
+            Token::Num(2),
+            Token::BitLShift,
+        ]);
+        assert_eq!(shunting_yard("c > 1 < 2"), expected);
+        assert_eq!(shunting_yard("c >> 1 << 2"), expected);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let rpn = |input: &str| parse_with_syntax(input, Syntax::V2).map(|e| e.to_rpn());
+        let expected = Ok(vec![
+            Token::Var(Var::X),
+            Token::Num(5),
+            Token::Add,
+            Token::Var(Var::Y),
+            Token::Lt,
+        ]);
+        assert_eq!(rpn("x + 5 < y"), expected);
+
+        let expected = Ok(vec![
+            Token::Var(Var::Lum),
+            Token::Num(2),
+            Token::BitRShift,
+            Token::Num(128),
+            Token::Gt,
+        ]);
+        assert_eq!(rpn("Y >> 2 > 128"), expected);
+
+        for (input, tok) in [
+            ("c <= b", Token::Le),
+            ("c >= b", Token::Ge),
+            ("c == b", Token::Eq),
+            ("c != b", Token::Ne),
+        ] {
+            assert_eq!(rpn(input).unwrap()[2], tok);
+            assert_eq!(shunting_yard(input).unwrap()[2], tok);
+        }
+
+        assert_eq!(
+            parse("c = b").unwrap_err().kind,
+            ParseErrorKind::UnexpectedToken("=".to_string())
+        );
+    }
+
+    #[test]
+    fn test_named_variables() {
+        let expected = shunting_yard("c + (b & Y) - N").unwrap();
+        assert_eq!(shunting_yard("color + (blur & lum) - noise"), Ok(expected));
+
+        let expr = parse("max(high, h)").unwrap().body;
+        assert_eq!(expr.to_string(), "max(H, h)");
+        assert_eq!(
+            parse("c + lumen").unwrap_err().kind,
+            ParseErrorKind::UnknownVariable("lumen".to_string())
+        );
+        // Variables added since go-glitch have no letter, to keep them free.
+        assert_eq!(parse("c ^ frame").unwrap().to_string(), "c ^ frame");
+        assert_eq!(
+            parse("c ^ f").unwrap_err().kind,
+            ParseErrorKind::UnknownVariable("f".to_string())
+        );
+    }
+
+    #[test]
+    fn test_let_bindings() {
+        let program = parse("let k = c ^ b; let m = k & 240; m + (k ? 2)").unwrap();
+        assert_eq!(program.bindings.len(), 2);
+        assert_eq!(program.bindings[1].span, Span::new(15, 31));
+        assert_eq!(
+            program.to_string(),
+            "let k = c ^ b; let m = k & 240; m + (k ? 2)"
+        );
+
+        let expected = vec![
+            Token::Var(Var::Color),
+            Token::Var(Var::Blur),
+            Token::BitXor,
+            Token::Store(0),
+            Token::Local(0),
+            Token::Num(240),
+            Token::BitAnd,
+            Token::Store(1),
+            Token::Local(1),
+            Token::Local(0),
+            Token::Num(2),
+            Token::Greater,
+            Token::Add,
+        ];
+        assert_eq!(program.to_rpn(), expected);
+    }
+
+    #[test]
+    fn test_let_scoping() {
+        let program = parse("let k = c;\nlet k = k + 1;\nk;").unwrap();
+        match &program.bindings[1].value.kind {
+            ExprKind::Binary { lhs, .. } => {
+                assert!(matches!(lhs.kind, ExprKind::Local { slot: 0, .. }))
+            }
+            kind => panic!("expected binary expression, got {:?}", kind),
+        }
+        assert!(matches!(program.body.kind, ExprKind::Local { slot: 1, .. }));
+
+        let kind = |input: &str| parse(input).unwrap_err().kind;
+        assert_eq!(
+            kind("let k = k + 1; k"),
+            ParseErrorKind::UnknownVariable("k".to_string())
+        );
+        assert_eq!(
+            kind("let blur = c; blur"),
+            ParseErrorKind::ReservedName("blur".to_string())
+        );
+        assert_eq!(
+            kind("let max = c; max"),
+            ParseErrorKind::ReservedName("max".to_string())
+        );
+        assert_eq!(kind("let k = c k"), ParseErrorKind::Expected(";"));
+        assert_eq!(kind("let k = c;"), ParseErrorKind::EmptyExpression);
+        assert_eq!(kind("let k c; k"), ParseErrorKind::Expected("="));
+    }
+
+    #[test]
+    fn test_channel_lists() {
+        let program = parse("[c, h, v] ^ N").unwrap();
+        assert!(program.alpha.is_none());
+        let expected = vec![
+            Token::Var(Var::Color),
+            Token::Var(Var::HFlip),
+            Token::Var(Var::VFlip),
+            Token::Channels,
+            Token::Var(Var::Noise),
+            Token::BitXor,
+        ];
+        assert_eq!(program.to_rpn(), expected);
+
+        let program = parse("let k = c; [k, k >> 1, 0, Y]").unwrap();
+        assert!(matches!(program.body.kind, ExprKind::Channels(ref items) if items.len() == 3));
+        assert_eq!(program.alpha.map(|a| a.kind), Some(ExprKind::Var(Var::Lum)));
+        assert_eq!(
+            parse("[c, b, h, 255]").unwrap().to_string(),
+            "[c, b, h, 255]"
+        );
+    }
+
+    #[test]
+    fn test_channel_list_errors() {
+        let err = |input: &str| parse(input).unwrap_err();
+        assert_eq!(err("[c, b]").kind, ParseErrorKind::ChannelCount(2));
+        assert_eq!(err("[c, b, h, v, d]").span, Span::new(0, 15));
+        assert_eq!(err("[c, b, h").kind, ParseErrorKind::UnbalancedBracket);
+        assert_eq!(err("c]").kind, ParseErrorKind::UnbalancedBracket);
+        assert_eq!(err("[c, b, h, 255] + 1").kind, ParseErrorKind::NestedAlpha);
+        assert_eq!(err("[c, b, h, 255] + 1").span, Span::new(0, 14));
+    }
+
+    #[test]
+    fn test_swizzle() {
+        let expr = parse("-h.bgr + c.g").unwrap().body;
+        assert_eq!(expr.to_string(), "-h.bgr + c.g");
+        let expected = vec![
+            Token::Var(Var::HFlip),
+            Token::Swizzle([2, 1, 0]),
+            Token::Neg,
+            Token::Var(Var::Color),
+            Token::Swizzle([1, 1, 1]),
+            Token::Add,
+        ];
+        assert_eq!(parse("-h.bgr + c.g").unwrap().to_rpn(), expected);
+
+        // `r` and `b` are variables on their own but channels after a dot.
+        let expected = vec![Token::Var(Var::Blur), Token::Swizzle([0, 0, 2])];
+        assert_eq!(shunting_yard("b.rrb"), Ok(expected));
+
+        let err = |input: &str| parse(input).unwrap_err();
+        assert_eq!(
+            err("c.rg").kind,
+            ParseErrorKind::InvalidSwizzle("rg".to_string())
+        );
+        assert_eq!(err("c + c.x").span, Span::new(5, 7));
+        assert_eq!(err("c.").kind, ParseErrorKind::Expected("channels"));
+    }
+
+    #[test]
+    fn test_offsets() {
+        let expr = parse("blur[x >> 4, -2].g").unwrap().body;
+        assert_eq!(expr.to_string(), "b[x >> 4, -2].g");
+        let expected = vec![
+            Token::Var(Var::Color),
+            Token::Num(1),
+            Token::Num(2),
+            Token::Neg,
+            Token::Sample(Var::Color),
+            Token::Add,
+        ];
+        assert_eq!(shunting_yard("c + c[1, -2]"), Ok(expected));
+
+        // `[` in prefix position is still a channel list.
+        assert!(matches!(
+            parse("c * [1, 2, 3]").unwrap().body.kind,
+            ExprKind::Binary { .. }
+        ));
+
+        let err = |input: &str| parse(input).unwrap_err();
+        assert_eq!(
+            err("x[1, 0]").kind,
+            ParseErrorKind::NotSpatial("x".to_string())
+        );
+        assert_eq!(err("c[1, 2, 3]").kind, ParseErrorKind::OffsetCount(3));
+        assert_eq!(err("c[1, 2, 3]").span, Span::new(1, 10));
+        assert_eq!(err("c[1, 2").kind, ParseErrorKind::UnbalancedBracket);
+    }
+
+    #[test]
+    fn test_remap() {
+        let program = parse("let w = Y >> 3; x + w, y").unwrap();
+        assert_eq!(program.to_string(), "let w = Y >> 3; x + w, y");
+        assert_eq!(program.remap.map(|y| y.kind), Some(ExprKind::Var(Var::Y)));
+        let expected = vec![Token::Var(Var::X), Token::Var(Var::Y), Token::Remap];
+        assert_eq!(shunting_yard("x, y;"), Ok(expected));
+
+        let err = |input: &str| parse(input).unwrap_err();
+        assert_eq!(err("[x, x, x, 1], y").kind, ParseErrorKind::NestedAlpha);
+        assert_eq!(err("x,").kind, ParseErrorKind::DanglingOperator);
+    }
+
+    #[test]
+    fn test_noise_functions() {
+        let expected = vec![
+            Token::Call(Func::Hash, 0),
+            Token::Num(16),
+            Token::Call(Func::Perlin, 1),
+            Token::Var(Var::Frame),
+            Token::Call(Func::ValueNoise, 1),
+            Token::Call(Func::Max, 2),
+            Token::BitXor,
+        ];
+        assert_eq!(
+            shunting_yard("hash() ^ max(perlin(16), vnoise(frame))"),
+            Ok(expected)
+        );
+
+        let err = parse("hash(1, 2)").unwrap_err();
+        assert_eq!(err.kind.to_string(), "hash takes 0 to 1 arguments, found 2");
+        let err = parse("perlin()").unwrap_err();
+        assert_eq!(err.kind.to_string(), "perlin takes 1 argument, found 0");
+    }
+
+    #[test]
+    fn test_kernel_radius() {
+        let expected = vec![
+            Token::Num(4),
+            Token::Call(Func::Blur, 1),
+            Token::Var(Var::X),
+            Token::Call(Func::High, 1),
+            Token::Add,
+            Token::Var(Var::Blur),
+            Token::Sub,
+        ];
+        assert_eq!(shunting_yard("b(4) + high(x) - blur"), Ok(expected));
+        assert_eq!(parse("L (2)").unwrap().to_string(), "low(2)");
+        assert_eq!(
+            parse("edge(1)").unwrap().body.kind,
+            ExprKind::Call {
+                func: Func::Edge,
+                args: vec![Expr::new(ExprKind::Num(1), Span::new(5, 6))],
+            }
+        );
+
+        let err = parse("e(1, 2)").unwrap_err();
+        assert_eq!(err.kind.to_string(), "edge takes 1 argument, found 2");
+        assert_eq!(err.span, Span::new(0, 7));
+        assert_eq!(
+            parse("c(1)").unwrap_err().kind,
+            ParseErrorKind::MissingOperator
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let expected = vec![
+            Token::Num(2),
+            Token::Call(Func::Gauss, 1),
+            Token::Call(Func::Sobel, 0),
+            Token::Num(1),
+            Token::Var(Var::X),
+            Token::Call(Func::Unsharp, 2),
+            Token::Call(Func::Max, 3),
+        ];
+        assert_eq!(
+            shunting_yard("max(gauss(2), sobel(), unsharp(1, x))"),
+            Ok(expected)
+        );
+
+        let err = |input: &str| parse(input).unwrap_err().kind.to_string();
+        assert_eq!(err("laplace(1)"), "laplace takes 0 arguments, found 1");
+        assert_eq!(err("unsharp()"), "unsharp takes 1 to 2 arguments, found 0");
+        assert_eq!(err("angle"), "unknown variable 'angle'");
+    }
+
+    #[test]
+    fn test_kernel_declarations() {
+        let program =
+            parse("kernel k = [[0, -1, 0], [-1, 5, -1], [0, -1, 0]] / 2 clamp; k + c").unwrap();
+        let kernel = &program.kernels[0];
+        assert_eq!(
+            kernel.weights,
+            vec![vec![0, -1, 0], vec![-1, 5, -1], vec![0, -1, 0]]
+        );
+        assert_eq!((kernel.divisor, kernel.overflow), (2, Overflow::Clamp));
+        assert_eq!(
+            program.to_rpn(),
+            vec![Token::Convolve(0), Token::Var(Var::Color), Token::Add]
+        );
+        assert_eq!(
+            program.to_string(),
+            "kernel k = [[0, -1, 0], [-1, 5, -1], [0, -1, 0]] / 2 clamp; k + c"
+        );
+
+        let program =
+            parse("kernel a = [[1, 1, 1]]; let b2 = a; kernel sharp = [[3]]; sharp - b2").unwrap();
+        assert_eq!(program.kernels[0].divisor, 1);
+        assert_eq!(program.kernels[0].overflow, Overflow::Wrap);
+        assert_eq!(program.to_rpn()[2], Token::Convolve(1));
+
+        let err = |input: &str| parse(input).unwrap_err().kind.to_string();
+        assert_eq!(
+            err("kernel k = [[1, 2], [3]]; k"),
+            "invalid kernel, rows have different lengths"
+        );
+        assert_eq!(
+            err("kernel k = [[1, 2]]; k"),
+            "invalid kernel, sizes have to be odd to have a center"
+        );
+        assert_eq!(
+            err("kernel k = [[1]] / 0; k"),
+            "invalid kernel, the divisor can not be 0"
+        );
+        assert_eq!(err("kernel k = [[1]] round; k"), "unexpected 'round'");
+        assert_eq!(
+            err("kernel k = [[1]]; kernel k = [[2]]; k"),
+            "'k' is already declared"
+        );
+        assert_eq!(
+            err("kernel k = [[1]]; let k = 2; k"),
+            "'k' is already declared"
+        );
+        assert_eq!(
+            err("let k = 2; kernel k = [[1]]; k"),
+            "'k' is already declared"
+        );
+        assert_eq!(
+            err("kernel max = [[1]]; c"),
+            "'max' is a built-in name and can not be bound"
+        );
+        assert_eq!(err("kernel k = [[1, c]]; k"), "unexpected 'c'");
+        assert_eq!(err("c + kernel"), "unexpected 'kernel'");
+    }
+
+    #[test]
+    fn test_kernel_file() {
+        let kernels = parse_kernels(
+            "kernel sharpen = [[0, -1, 0], [-1, 5, -1], [0, -1, 0]] clamp;\nkernel box = [[1, 1, 1]] / 3;\n",
+            Syntax::Legacy,
+        )
+        .unwrap();
+        assert_eq!(kernels.len(), 2);
+
+        let program =
+            parse_with_kernels("kernel own = [[2]]; box ^ own", Syntax::Legacy, &kernels).unwrap();
+        assert_eq!(program.kernels.len(), 3);
+        assert_eq!(
+            program.to_rpn(),
+            vec![Token::Convolve(1), Token::Convolve(2), Token::BitXor]
+        );
+
+        let err = parse_kernels("kernel a = [[1]];\nc + 1", Syntax::Legacy).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedToken("c".to_string()));
+        assert_eq!(err.span, Span::new(18, 19));
+    }
 }
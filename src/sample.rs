@@ -0,0 +1,97 @@
+use image::{DynamicImage, Rgba};
+
+use crate::edge::EdgeMode;
+use crate::eval::three_rule;
+
+/// How a remap program reads the input between pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Sampling {
+    /// The closest pixel.
+    #[default]
+    Nearest,
+    /// A blend of the four surrounding pixels.
+    Bilinear,
+}
+
+/// Turns a normalized coordinate given by a remap program into pixels. It is
+/// taken relative to the normalized position of the pixel itself, so `x, y`
+/// reads every pixel from exactly where it is even when the image is wider than
+/// 256 pixels.
+pub fn source_coord(pos: u32, value: u8, len: u32) -> f64 {
+    let offset = f64::from(value) - f64::from(three_rule(pos, len));
+    f64::from(pos) + offset * f64::from(len) / 255.0
+}
+
+/// Reads the input at a fractional position, pixels outside of the image go
+/// through the edge mode. `own` is the pixel being evaluated, which shows through
+/// a transparent edge.
+pub fn sample(
+    input: &DynamicImage,
+    (x, y): (f64, f64),
+    sampling: Sampling,
+    edge: EdgeMode,
+    own: Rgba<u8>,
+) -> Rgba<u8> {
+    let fetch = |x: f64, y: f64| {
+        let pixel = edge.read(input, (x as i64, y as i64)).unwrap_or(own);
+        pixel.0.map(f64::from)
+    };
+
+    match sampling {
+        Sampling::Nearest => Rgba(fetch(x.round(), y.round()).map(|v| v as u8)),
+        Sampling::Bilinear => {
+            let (x0, y0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - x0, y - y0);
+            let [p00, p10, p01, p11] = [
+                fetch(x0, y0),
+                fetch(x0 + 1.0, y0),
+                fetch(x0, y0 + 1.0),
+                fetch(x0 + 1.0, y0 + 1.0),
+            ];
+
+            let mut out = [0u8; 4];
+            for (i, v) in out.iter_mut().enumerate() {
+                let top = p00[i] * (1.0 - fx) + p10[i] * fx;
+                let bottom = p01[i] * (1.0 - fx) + p11[i] * fx;
+                *v = (top * (1.0 - fy) + bottom * fy).round() as u8;
+            }
+            Rgba(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::image;
+
+    #[test]
+    fn test_identity_coords() {
+        for len in [1, 3, 255, 256, 1000] {
+            for pos in 0..len {
+                let value = three_rule(pos, len);
+                assert_eq!(source_coord(pos, value, len), f64::from(pos));
+            }
+        }
+    }
+
+    #[test]
+    fn test_nearest() {
+        let img = image(4, 2);
+        let own = Rgba([1, 2, 3, 4]);
+        let at = |x, y, edge| sample(&img, (x, y), Sampling::Nearest, edge, own).0;
+        assert_eq!(at(1.4, 0.6, EdgeMode::Zero), [48, 8, 54, 254]);
+        assert_eq!(at(-1.0, 0.0, EdgeMode::Zero), [0, 0, 0, 0]);
+        assert_eq!(at(-1.0, 0.0, EdgeMode::Transparent), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_bilinear() {
+        let img = image(4, 2);
+        let at = |x, y, edge| sample(&img, (x, y), Sampling::Bilinear, edge, Rgba([0; 4])).0;
+        assert_eq!(at(2.0, 1.0, EdgeMode::Zero), [85, 13, 45, 253]);
+        assert_eq!(at(1.5, 0.25, EdgeMode::Zero), [58, 5, 23, 254]);
+        assert_eq!(at(3.5, 0.0, EdgeMode::Clamp), [111, 3, 27, 252]);
+        assert_eq!(at(3.5, 0.0, EdgeMode::Zero), [56, 2, 14, 126]);
+    }
+}
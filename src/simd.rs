@@ -0,0 +1,549 @@
+use std::ops::Range;
+
+use image::{DynamicImage, GenericImageView, Rgba};
+
+use crate::ast::{BinOp, Func, UnOp, Var};
+use crate::compile::{Compiled, Op};
+use crate::eval::{self, Inputs};
+
+/// Components processed together. The operators work on fixed size arrays of this
+/// many bytes, which the compiler turns into vector instructions.
+pub const LANES: usize = 32;
+
+/// One component of a value at every pixel of a row.
+type Plane = Vec<u8>;
+
+/// A value at every pixel of a row, one plane per color component.
+type Planes = [Plane; 3];
+
+/// Whether the program can be evaluated a row at a time. `s` needs the pixel
+/// above and `N`, `r` draw numbers in the order pixels are visited, so they are
+/// left to the interpreter, as are coordinates.
+pub fn supports(program: &Compiled) -> bool {
+    program.remap.is_none()
+        && !program
+            .code
+            .iter()
+            .any(|op| matches!(op, Op::Var(Var::Saved | Var::Noise | Var::Rand)))
+}
+
+/// Applies `f` to each pair of bytes of `a` and `b`, writing back into `a`.
+#[inline(always)]
+fn zip(a: &mut [u8], b: &[u8], f: impl Fn(u8, u8) -> u8) {
+    let mut a_chunks = a.chunks_exact_mut(LANES);
+    let mut b_chunks = b.chunks_exact(LANES);
+    for (a, b) in (&mut a_chunks).zip(&mut b_chunks) {
+        let a: &mut [u8; LANES] = a.try_into().unwrap();
+        let b: &[u8; LANES] = b.try_into().unwrap();
+        for i in 0..LANES {
+            a[i] = f(a[i], b[i]);
+        }
+    }
+    for (a, b) in a_chunks
+        .into_remainder()
+        .iter_mut()
+        .zip(b_chunks.remainder())
+    {
+        *a = f(*a, *b);
+    }
+}
+
+#[inline(always)]
+fn map(a: &mut [u8], f: impl Fn(u8) -> u8) {
+    let mut chunks = a.chunks_exact_mut(LANES);
+    for a in &mut chunks {
+        let a: &mut [u8; LANES] = a.try_into().unwrap();
+        for v in a.iter_mut() {
+            *v = f(*v);
+        }
+    }
+    for v in chunks.into_remainder() {
+        *v = f(*v);
+    }
+}
+
+/// `a = a op b` for every byte, with the same wrapping results as the interpreter.
+pub fn binary(op: BinOp, a: &mut [u8], b: &[u8]) {
+    assert_eq!(a.len(), b.len());
+    match op {
+        BinOp::Add => zip(a, b, u8::wrapping_add),
+        BinOp::Sub => zip(a, b, u8::wrapping_sub),
+        BinOp::Mul => zip(a, b, u8::wrapping_mul),
+        BinOp::Div => zip(a, b, eval::div),
+        BinOp::Mod => zip(a, b, eval::modu),
+        BinOp::Pow => zip(a, b, |a, b| a.wrapping_pow(b.into())),
+        BinOp::BitAnd => zip(a, b, |a, b| a & b),
+        BinOp::BitOr => zip(a, b, |a, b| a | b),
+        BinOp::BitAndNot => zip(a, b, eval::bit_and_not),
+        BinOp::BitXor => zip(a, b, |a, b| a ^ b),
+        BinOp::BitLShift => zip(a, b, |a, b| a << (b & 7)),
+        BinOp::BitRShift => zip(a, b, |a, b| a >> (b & 7)),
+        BinOp::Weight => zip(a, b, eval::weight),
+        BinOp::Greater | BinOp::Gt => zip(a, b, |a, b| eval::mask(a > b)),
+        BinOp::Lt => zip(a, b, |a, b| eval::mask(a < b)),
+        BinOp::Le => zip(a, b, |a, b| eval::mask(a <= b)),
+        BinOp::Eq => zip(a, b, |a, b| eval::mask(a == b)),
+        BinOp::Ne => zip(a, b, |a, b| eval::mask(a != b)),
+        BinOp::Ge => zip(a, b, |a, b| eval::mask(a >= b)),
+    }
+}
+
+/// `a = op a` for every byte.
+pub fn unary(op: UnOp, a: &mut [u8]) {
+    match op {
+        UnOp::Neg => map(a, u8::wrapping_neg),
+        UnOp::Not => map(a, |v| !v),
+        UnOp::Abs => map(a, |v| (v as i8).unsigned_abs()),
+    }
+}
+
+/// Takes each byte from `a` where `mask` is non zero and from `b` otherwise,
+/// writing into `mask`.
+fn select(mask: &mut [u8], a: &[u8], b: &[u8]) {
+    // Without branches, so it vectorizes like the operators.
+    let pick = |m: u8, a: u8, b: u8| {
+        let m = eval::mask(m != 0);
+        (a & m) | (b & !m)
+    };
+    let mut m_chunks = mask.chunks_exact_mut(LANES);
+    let mut a_chunks = a.chunks_exact(LANES);
+    let mut b_chunks = b.chunks_exact(LANES);
+    for ((m, a), b) in (&mut m_chunks).zip(&mut a_chunks).zip(&mut b_chunks) {
+        let m: &mut [u8; LANES] = m.try_into().unwrap();
+        let a: &[u8; LANES] = a.try_into().unwrap();
+        let b: &[u8; LANES] = b.try_into().unwrap();
+        for i in 0..LANES {
+            m[i] = pick(m[i], a[i], b[i]);
+        }
+    }
+    let rest = m_chunks.into_remainder().iter_mut();
+    for ((m, a), b) in rest.zip(a_chunks.remainder()).zip(b_chunks.remainder()) {
+        *m = pick(*m, *a, *b);
+    }
+}
+
+/// Evaluates programs accepted by [`supports`] over a row of pixels at a time,
+/// running each operation across the whole row instead of pixel by pixel.
+#[derive(Debug)]
+pub struct RowMachine {
+    len: usize,
+    /// Allocated up front, only the first `depth` entries hold values.
+    stack: Vec<Planes>,
+    depth: usize,
+    slots: Vec<Planes>,
+    computed: Vec<bool>,
+    returns: Vec<usize>,
+    /// Arguments taken off the stack while the result is written over them.
+    saved: [Planes; 2],
+    /// The color of the row while its alpha is evaluated.
+    color: Planes,
+}
+
+impl RowMachine {
+    /// A machine for rows of `len` pixels.
+    pub fn new(program: &Compiled, len: usize) -> Self {
+        let planes = || [vec![0; len], vec![0; len], vec![0; len]];
+        RowMachine {
+            len,
+            stack: (0..program.max_stack).map(|_| planes()).collect(),
+            depth: 0,
+            slots: (0..program.bindings.len()).map(|_| planes()).collect(),
+            computed: vec![false; program.bindings.len()],
+            returns: Vec::with_capacity(program.bindings.len()),
+            saved: [planes(), planes()],
+            color: planes(),
+        }
+    }
+
+    /// Evaluates the pixels `xs` of row `y`, giving the same colors as
+    /// [`eval::eval`] at each of them.
+    pub fn eval(
+        &mut self,
+        program: &Compiled,
+        inputs: &mut Inputs,
+        input: &DynamicImage,
+        y: u32,
+        xs: Range<u32>,
+    ) -> Vec<Rgba<u8>> {
+        assert_eq!(xs.len(), self.len);
+        self.computed.fill(false);
+
+        let row = Row { y, xs: xs.clone() };
+        self.run(program, program.body, inputs, &row);
+        if let Some(alpha) = program.alpha {
+            std::mem::swap(&mut self.color, &mut self.stack[0]);
+            self.run(program, alpha, inputs, &row);
+        }
+        let (color, alpha) = match program.alpha {
+            Some(_) => (&self.color, Some(&self.stack[0][0])),
+            None => (&self.stack[0], None),
+        };
+
+        xs.enumerate()
+            .map(|(i, x)| match input.get_pixel(x, y).0[3] {
+                0 => Rgba([0, 0, 0, 0]),
+                a => Rgba([
+                    color[0][i],
+                    color[1][i],
+                    color[2][i],
+                    alpha.map_or(a, |alpha| alpha[i]),
+                ]),
+            })
+            .collect()
+    }
+
+    /// Pushes an entry, its contents are whatever was there before.
+    fn push(&mut self) -> &mut Planes {
+        if self.depth == self.stack.len() {
+            self.stack
+                .push([vec![0; self.len], vec![0; self.len], vec![0; self.len]]);
+        }
+        self.depth += 1;
+        &mut self.stack[self.depth - 1]
+    }
+
+    /// The entries at `depth - n` and above.
+    fn top(&mut self, n: usize) -> &mut [Planes] {
+        &mut self.stack[self.depth - n..self.depth]
+    }
+
+    /// Pops the top `n` entries into `saved`, swapping the buffers so nothing is
+    /// copied or allocated.
+    fn save(&mut self, n: usize) {
+        let top = &mut self.stack[self.depth - n..self.depth];
+        for (saved, entry) in self.saved.iter_mut().zip(top) {
+            std::mem::swap(saved, entry);
+        }
+        self.depth -= n;
+    }
+
+    /// Fills the top entry with a value computed at each pixel, `f` also gets the
+    /// entries last [saved](Self::save).
+    fn fill_each(
+        &mut self,
+        row: &Row,
+        mut f: impl FnMut((u32, u32), usize, &[Planes; 2]) -> [u8; 3],
+    ) {
+        let top = &mut self.stack[self.depth - 1];
+        for (i, x) in row.xs.clone().enumerate() {
+            let v = f((x, row.y), i, &self.saved);
+            for lane in 0..3 {
+                top[lane][i] = v[lane];
+            }
+        }
+    }
+
+    /// Runs the code at `entry` up to its [`Op::Halt`], leaving the result just
+    /// past the top of the stack.
+    fn run(&mut self, program: &Compiled, entry: usize, inputs: &mut Inputs, row: &Row) {
+        let mut pc = entry;
+        loop {
+            let op = program.code[pc];
+            pc += 1;
+            match op {
+                Op::Num(n) => self.push().iter_mut().for_each(|plane| plane.fill(n)),
+                Op::Var(var) => {
+                    self.push();
+                    self.fill_each(row, |position, _, _| inputs.var(var, position));
+                }
+                Op::Convolve(slot) => {
+                    self.push();
+                    self.fill_each(row, |position, _, _| inputs.convolved(slot, position));
+                }
+                Op::Local(slot) => match self.computed[slot] {
+                    true => {
+                        let depth = self.depth;
+                        self.push();
+                        for (plane, value) in self.stack[depth].iter_mut().zip(&self.slots[slot]) {
+                            plane.copy_from_slice(value);
+                        }
+                    }
+                    false => {
+                        self.returns.push(pc);
+                        pc = program.bindings[slot];
+                    }
+                },
+                Op::Return(slot) => {
+                    let top = &self.stack[self.depth - 1];
+                    for (plane, value) in self.slots[slot].iter_mut().zip(top) {
+                        plane.copy_from_slice(value);
+                    }
+                    self.computed[slot] = true;
+                    pc = self.returns.pop().unwrap();
+                }
+                Op::Unary(op) => {
+                    for plane in &mut self.top(1)[0] {
+                        unary(op, plane);
+                    }
+                }
+                Op::Swizzle(lanes) => {
+                    self.save(1);
+                    self.push();
+                    let (top, old) = (&mut self.stack[self.depth - 1], &self.saved[0]);
+                    for lane in 0..3 {
+                        top[lane].copy_from_slice(&old[lanes[lane] as usize]);
+                    }
+                }
+                Op::Binary(op) => {
+                    let [a, b] = self.top(2) else { unreachable!() };
+                    for lane in 0..3 {
+                        binary(op, &mut a[lane], &b[lane]);
+                    }
+                    self.depth -= 1;
+                }
+                Op::Call(func, argc) if func.is_noise() => {
+                    self.save(argc);
+                    self.push();
+                    self.fill_each(row, |position, i, saved| {
+                        let arg = (argc == 1).then(|| saved[0].each_ref().map(|plane| plane[i]));
+                        inputs.noise(func, position, arg)
+                    });
+                }
+                Op::Call(func, argc) if func.is_kernel() => {
+                    self.save(argc);
+                    self.push();
+                    self.fill_each(row, |position, i, saved| {
+                        let lanes = saved
+                            .each_ref()
+                            .map(|arg| arg.each_ref().map(|plane| plane[i]));
+                        inputs.kernel(func, position, &lanes[..argc])
+                    });
+                }
+                Op::Call(func, argc) => {
+                    let args = self.top(argc);
+                    let (first, rest) = args.split_first_mut().unwrap();
+                    for lane in 0..3 {
+                        call(
+                            func,
+                            &mut first[lane],
+                            rest.iter().map(|arg| &arg[lane][..]),
+                        );
+                    }
+                    self.depth -= argc - 1;
+                }
+                Op::Offset(var) => {
+                    self.save(2);
+                    self.push();
+                    self.fill_each(row, |position, i, [dx, dy]| {
+                        inputs.offset(var, position, dx[0][i] as i8, dy[0][i] as i8)
+                    });
+                }
+                Op::Channels => {
+                    let [r, g, b] = self.top(3) else {
+                        unreachable!()
+                    };
+                    std::mem::swap(&mut r[1], &mut g[1]);
+                    std::mem::swap(&mut r[2], &mut b[2]);
+                    self.depth -= 2;
+                }
+                // A branch is only skipped when no pixel of the row takes it. The
+                // row never has `N` or `r`, so evaluating an unused one is harmless.
+                Op::SkipIfNone(target) => {
+                    let mask = &self.stack[self.depth - 1];
+                    if mask.iter().all(|plane| plane.iter().all(|v| *v == 0)) {
+                        self.push();
+                        pc = target;
+                    }
+                }
+                Op::SkipIfAll(target) => {
+                    let mask = &self.stack[self.depth - 2];
+                    if mask.iter().all(|plane| plane.iter().all(|v| *v != 0)) {
+                        self.push();
+                        pc = target;
+                    }
+                }
+                Op::Select => {
+                    let [mask, a, b] = self.top(3) else {
+                        unreachable!()
+                    };
+                    for lane in 0..3 {
+                        select(&mut mask[lane], &a[lane], &b[lane]);
+                    }
+                    self.depth -= 2;
+                }
+                Op::Halt => {
+                    self.depth -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The pixels a [`RowMachine`] is evaluating.
+struct Row {
+    y: u32,
+    xs: Range<u32>,
+}
+
+/// Calls a function on a component of each argument, writing into the first one.
+fn call<'a>(func: Func, first: &mut [u8], mut rest: impl Iterator<Item = &'a [u8]>) {
+    match func {
+        Func::Min => rest.for_each(|arg| zip(first, arg, u8::min)),
+        Func::Max => rest.for_each(|arg| zip(first, arg, u8::max)),
+        Func::Clamp => {
+            zip(first, rest.next().unwrap(), u8::max);
+            zip(first, rest.next().unwrap(), u8::min);
+        }
+        Func::Avg => {
+            let rest: Vec<&[u8]> = rest.collect();
+            let n = rest.len() as u32 + 1;
+            for (i, v) in first.iter_mut().enumerate() {
+                let sum = rest.iter().map(|arg| u32::from(arg[i])).sum::<u32>() + u32::from(*v);
+                *v = (sum / n) as u8;
+            }
+        }
+        Func::Lerp => {
+            let (b, t) = (rest.next().unwrap(), rest.next().unwrap());
+            for (i, v) in first.iter_mut().enumerate() {
+                *v = eval::lerp(*v, b[i], t[i]);
+            }
+        }
+        Func::Hash | Func::ValueNoise | Func::Perlin => {
+            unreachable!("noise depends on the pixel and is read through Inputs")
+        }
+        Func::Blur
+        | Func::Edge
+        | Func::High
+        | Func::Low
+        | Func::Gauss
+        | Func::Sobel
+        | Func::Angle
+        | Func::Laplace
+        | Func::Unsharp => {
+            unreachable!("kernels depend on the pixel and are read through Inputs")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::edge::EdgeMode;
+    use crate::eval::{fold_binary, fold_unary, EvalContext, Machine};
+    use crate::fixtures::image;
+    use crate::kernels::Kernels;
+    use crate::parser::parse;
+
+    const BINARY: [BinOp; 20] = [
+        BinOp::Add,
+        BinOp::Sub,
+        BinOp::Mul,
+        BinOp::Div,
+        BinOp::Mod,
+        BinOp::Pow,
+        BinOp::BitAnd,
+        BinOp::BitOr,
+        BinOp::BitAndNot,
+        BinOp::BitXor,
+        BinOp::BitLShift,
+        BinOp::BitRShift,
+        BinOp::Greater,
+        BinOp::Weight,
+        BinOp::Lt,
+        BinOp::Le,
+        BinOp::Eq,
+        BinOp::Ne,
+        BinOp::Ge,
+        BinOp::Gt,
+    ];
+
+    #[test]
+    fn test_binary_matches_scalar() {
+        // Every pair of operands, 65536 bytes is a whole number of chunks so the
+        // tail is covered by dropping the last few.
+        let a: Vec<u8> = (0..=u16::MAX).map(|v| (v >> 8) as u8).collect();
+        let b: Vec<u8> = (0..=u16::MAX).map(|v| v as u8).collect();
+        for op in BINARY {
+            for len in [a.len(), a.len() - 7] {
+                let mut out = a[..len].to_vec();
+                binary(op, &mut out, &b[..len]);
+                for i in 0..len {
+                    assert_eq!(
+                        out[i],
+                        fold_binary(op, a[i], b[i]),
+                        "{} {:?} {}",
+                        a[i],
+                        op,
+                        b[i]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unary_matches_scalar() {
+        for op in [UnOp::Neg, UnOp::Not, UnOp::Abs] {
+            let mut out: Vec<u8> = (0..=255).collect();
+            unary(op, &mut out);
+            for (v, out) in (0..=255).zip(out) {
+                assert_eq!(out, fold_unary(op, v), "{:?} {}", op, v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rows_match_interpreter() {
+        let expressions = [
+            "c + x * y - frame",
+            "let k = (c ^ h) % (y >> 3); [k, k # 2, v @ d, Y]",
+            "if c > 128 then b - e else H : L",
+            "if x < 20 then c else ~c",
+            "min(c, b, 90) + max(d, 30) - clamp(c, 20, 200) ^ avg(c, H, L) + lerp(c, e, x)",
+            "c[x >> 4, -2] + h[1, 1].gbr + abs (c - v)",
+            "hash(c) ^ vnoise(8) - perlin(x)",
+            "[c == b, c != e, c <= x | (c >= y)]",
+            "let k = c.gbr; [k, blur(2), h[1, -1], x ^ b[-1, 0] + perlin(y)]",
+            "if Y ? 90 then c[x >> 3, 1].bgr @ 160 else v # d[-1, 2].rrg",
+            "blur(x >> 2) - edge(2) + H(y) ^ L(c >> 5)",
+            "unsharp(2, x) ^ sobel() + angle() - laplace() + gauss(y >> 6)",
+            "kernel k = [[1, 2, 1], [0, 0, 0], [-1, -2, -1]] / 2 clamp; k ^ c",
+        ];
+        for width in [45, 65] {
+            let img = image(width, 9);
+            let xs = 0..width;
+            for expr in expressions {
+                let program = Compiled::new(&parse(expr).unwrap());
+                let mut machine = RowMachine::new(&program, xs.len());
+                let kernels =
+                    Kernels::new(&img, EdgeMode::Wrap).with_convolutions(&program.kernels);
+                let mut inputs = Inputs::new(&img, EdgeMode::Wrap, &kernels, 3, 2);
+
+                let mut interpreter = Machine::new(&program);
+                let mut rng = ChaCha8Rng::seed_from_u64(0);
+                for y in 0..img.height() {
+                    let row = machine.eval(&program, &mut inputs, &img, y, xs.clone());
+                    for (x, row) in xs.clone().zip(row) {
+                        let ctx = EvalContext {
+                            seed: 3,
+                            frame: 2,
+                            ..EvalContext::for_test(
+                                &program,
+                                &mut interpreter,
+                                &kernels,
+                                &mut rng,
+                                &img,
+                                (x, y),
+                            )
+                        };
+                        let expected = eval::eval(ctx, &img).unwrap();
+                        assert_eq!(row, expected, "{} at ({}, {})", expr, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_supports() {
+        let supports = |expr| supports(&Compiled::new(&parse(expr).unwrap()));
+        assert!(supports("b + c[1, 2] ^ perlin(4)"));
+        assert!(!supports("c + s"));
+        assert!(!supports("if x > 3 then N else r"));
+        assert!(!supports("x, y"));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::ast::{Expr, ExprKind, Program, Span};
+use crate::error::{ParseError, ParseErrorKind};
+use crate::parser::Token;
+
+/// Checks a parsed program before any pixel is evaluated.
+pub fn validate(program: &Program) -> Result<(), ParseError> {
+    for binding in &program.bindings {
+        check_channels(&binding.value)?;
+    }
+    check_channels(&program.body)?;
+    if let Some(y) = &program.remap {
+        check_channels(y)?;
+    }
+    check_stack_depth(&program.to_rpn_spanned())
+}
+
+/// Rejects `[r, g, b, a]` lists left in the tree, only a list making up the
+/// whole body can set alpha.
+fn check_channels(expr: &Expr) -> Result<(), ParseError> {
+    match &expr.kind {
+        ExprKind::Channels(items) if items.len() != 3 => {
+            Err(ParseError::new(ParseErrorKind::NestedAlpha, expr.span))
+        }
+        ExprKind::Num(_)
+        | ExprKind::Var(_)
+        | ExprKind::Local { .. }
+        | ExprKind::Convolve { .. } => Ok(()),
+        ExprKind::Unary { operand, .. } | ExprKind::Swizzle { operand, .. } => {
+            check_channels(operand)
+        }
+        ExprKind::Binary { lhs, rhs, .. }
+        | ExprKind::Offset {
+            dx: lhs, dy: rhs, ..
+        } => {
+            check_channels(lhs)?;
+            check_channels(rhs)
+        }
+        ExprKind::Call { args: items, .. } | ExprKind::Channels(items) => {
+            items.iter().try_for_each(check_channels)
+        }
+        ExprKind::Cond {
+            cond,
+            if_true,
+            if_false,
+        } => {
+            check_channels(cond)?;
+            check_channels(if_true)?;
+            check_channels(if_false)
+        }
+        ExprKind::Group(inner) => check_channels(inner),
+    }
+}
+
+/// Simulates the evaluation stack over a postfix token stream, rejecting streams
+/// that would pop an empty stack or finish with anything but a single value.
+pub fn check_stack_depth(tokens: &[(Token, Span)]) -> Result<(), ParseError> {
+    // Spans of the values currently on the stack, so leftovers can be reported.
+    let mut stack: Vec<Span> = Vec::with_capacity(tokens.len());
+
+    for (tok, span) in tokens {
+        let (pops, pushes) = tok
+            .arity()
+            .ok_or(ParseError::new(ParseErrorKind::UnbalancedParen, *span))?;
+
+        if stack.len() < pops {
+            return Err(ParseError::new(ParseErrorKind::DanglingOperator, *span));
+        }
+
+        let operands = stack.split_off(stack.len() - pops);
+        let result = operands.into_iter().fold(*span, Span::to);
+        stack.extend(std::iter::repeat_n(result, pushes));
+    }
+
+    match stack.as_slice() {
+        [_] => Ok(()),
+        [] => Err(ParseError::new(
+            ParseErrorKind::EmptyExpression,
+            Span::default(),
+        )),
+        [_, extra, ..] => Err(ParseError::new(ParseErrorKind::MissingOperator, *extra)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Var;
+
+    fn spanned(tokens: &[Token]) -> Vec<(Token, Span)> {
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, tok)| (*tok, Span::new(i, i + 1)))
+            .collect()
+    }
+
+    #[test]
+    fn test_balanced_stream() {
+        let tokens = spanned(&[Token::Var(Var::Color), Token::Num(5), Token::Add]);
+        assert_eq!(check_stack_depth(&tokens), Ok(()));
+    }
+
+    #[test]
+    fn test_underflow() {
+        let tokens = spanned(&[Token::Var(Var::Color), Token::Add]);
+        let err = check_stack_depth(&tokens).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::DanglingOperator);
+        assert_eq!(err.span, Span::new(1, 2));
+    }
+
+    #[test]
+    fn test_leftover_values() {
+        let tokens = spanned(&[Token::Var(Var::Color), Token::Var(Var::Color)]);
+        let err = check_stack_depth(&tokens).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::MissingOperator);
+        assert_eq!(err.span, Span::new(1, 2));
+    }
+
+    #[test]
+    fn test_empty_stream() {
+        let err = check_stack_depth(&[]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::EmptyExpression);
+    }
+
+    #[test]
+    fn test_parenthesis_in_stream() {
+        let tokens = spanned(&[Token::LeftParen, Token::Var(Var::Color)]);
+        let err = check_stack_depth(&tokens).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnbalancedParen);
+    }
+}